@@ -0,0 +1,290 @@
+use crate::error::{Error, Result};
+use crate::settings::BackupSettings;
+use crate::util::write_atomically;
+
+use chrono::Utc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "manifest.jsonl";
+// Appended to the directory of a run once it's undone, so that the next undo goes to the run before
+const UNDONE_SUFFIX: &str = ".undone";
+
+// Copies of the files a run modified, so that undo-last-run can restore them. Each run has its own
+// timestamped directory in the backup directory, holding the copies and a JSONL manifest of what the run
+// did to each file, appended (and synced) before and after each write so that it's complete even if the
+// run is killed. The directory is only created once the run modifies a file.
+pub struct Backup {
+    settings: BackupSettings,
+    dir: PathBuf,
+    manifest: Option<fs::File>,
+    saved: HashSet<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum Entry {
+    // The file existed before the run, and was copied to backup (a file name in the run directory)
+    Saved { path: PathBuf, backup: String },
+    // The file didn't exist before the run
+    Created { path: PathBuf },
+    // What the run wrote to the file, to tell whether it was modified since
+    Written { path: PathBuf, hash: String },
+    Moved { from: PathBuf, to: PathBuf },
+}
+
+// What undo-last-run did
+pub struct UndoSummary {
+    pub run: String,
+    pub restored: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub moved_back: Vec<PathBuf>,
+    // Files modified since the run, which were left alone
+    pub skipped: Vec<PathBuf>,
+}
+
+fn content_hash(content: &[u8]) -> String {
+    format!("{:x}", md5::compute(content))
+}
+
+fn runs(backup_dir: &Path) -> Result<Vec<PathBuf>> {
+    // Return the directories of the runs, oldest first
+    if !backup_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut runs: Vec<PathBuf> = fs::read_dir(backup_dir)
+        .map_err(Error::io(backup_dir))?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.join(MANIFEST).is_file())
+        .collect();
+    runs.sort();
+    Ok(runs)
+}
+
+impl Backup {
+    pub fn start(settings: &BackupSettings) -> Option<Self> {
+        if !settings.enabled {
+            return None;
+        }
+        let dir = settings
+            .dir
+            .join(Utc::now().format("%Y%m%dT%H%M%S%.3fZ").to_string());
+        Some(Self {
+            settings: settings.clone(),
+            dir,
+            manifest: None,
+            saved: HashSet::new(),
+        })
+    }
+
+    fn manifest(&mut self) -> Result<&mut fs::File> {
+        // Create the directory of this run on first use, and remove the oldest ones beyond the retention
+        if self.manifest.is_none() {
+            fs::create_dir_all(&self.dir).map_err(Error::io(&self.dir))?;
+            let manifest_path = self.dir.join(MANIFEST);
+            let manifest = fs::File::create(&manifest_path).map_err(Error::io(&manifest_path))?;
+            let runs = runs(&self.settings.dir)?;
+            let keep_runs = self.settings.keep_runs.max(1);
+            for old_run in runs.iter().take(runs.len().saturating_sub(keep_runs)) {
+                fs::remove_dir_all(old_run).map_err(Error::io(old_run))?;
+            }
+            self.manifest = Some(manifest);
+        }
+        Ok(self.manifest.as_mut().unwrap())
+    }
+
+    fn append(&mut self, entry: &Entry) -> Result<()> {
+        let manifest_path = self.dir.join(MANIFEST);
+        let manifest = self.manifest()?;
+        writeln!(manifest, "{}", serde_json::to_string(entry)?)
+            .and_then(|_| manifest.sync_data())
+            .map_err(Error::io(manifest_path))
+    }
+
+    pub fn save(&mut self, path: &Path) -> Result<()> {
+        // Keep the version of the file from before the run, the first time the run modifies it
+        if self.saved.contains(path) {
+            return Ok(());
+        }
+        if !path.exists() {
+            self.append(&Entry::Created {
+                path: path.to_path_buf(),
+            })?;
+            self.saved.insert(path.to_path_buf());
+            return Ok(());
+        }
+        let backup = format!(
+            "{}-{}",
+            self.saved.len() + 1,
+            path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        self.manifest()?;
+        let backup_path = self.dir.join(&backup);
+        fs::copy(path, &backup_path).map_err(Error::io(path))?;
+        fs::File::open(&backup_path)
+            .and_then(|file| file.sync_all())
+            .map_err(Error::io(&backup_path))?;
+        self.append(&Entry::Saved {
+            path: path.to_path_buf(),
+            backup,
+        })?;
+        self.saved.insert(path.to_path_buf());
+        Ok(())
+    }
+
+    pub fn written(&mut self, path: &Path, content: &str) -> Result<()> {
+        self.append(&Entry::Written {
+            path: path.to_path_buf(),
+            hash: content_hash(content.as_bytes()),
+        })
+    }
+
+    pub fn moved(&mut self, from: &Path, to: &Path) -> Result<()> {
+        self.append(&Entry::Moved {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        })
+    }
+}
+
+pub fn undo_last_run(settings: &BackupSettings) -> Result<UndoSummary> {
+    // Restore the files modified by the last run that wasn't undone yet: edited files get their previous
+    // version back, created files are removed and moved files are moved back. Files modified since the
+    // run are left alone.
+    let run_dir = runs(&settings.dir)?
+        .into_iter()
+        .rev()
+        .find(|run| !run.to_string_lossy().ends_with(UNDONE_SUFFIX))
+        .ok_or_else(|| Error::Config(format!("No run to undo in {}", settings.dir.display())))?;
+    let manifest_path = run_dir.join(MANIFEST);
+    let contents = fs::read_to_string(&manifest_path).map_err(Error::io(&manifest_path))?;
+    // A line cut short by a crash is ignored: its write didn't happen, or its file is skipped
+    let entries: Vec<Entry> = contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    let mut written: HashMap<&Path, &str> = HashMap::new();
+    for entry in &entries {
+        if let Entry::Written { path, hash } = entry {
+            written.insert(path, hash);
+        }
+    }
+    // Whether the file still has what the run wrote to it (or was never written, if the run was killed)
+    let unchanged = |path: &Path| match (written.get(path), fs::read(path)) {
+        (Some(hash), Ok(content)) => content_hash(&content) == *hash,
+        (Some(_), Err(_)) => false,
+        (None, _) => true,
+    };
+
+    let mut summary = UndoSummary {
+        run: run_dir
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        restored: Vec::new(),
+        removed: Vec::new(),
+        moved_back: Vec::new(),
+        skipped: Vec::new(),
+    };
+    for entry in entries.iter().rev() {
+        match entry {
+            Entry::Written { .. } => {}
+            Entry::Saved { path, backup } if unchanged(path) => {
+                let backup_path = run_dir.join(backup);
+                let content = fs::read(&backup_path).map_err(Error::io(&backup_path))?;
+                write_atomically(path, &content)?;
+                info!("Restored {}", path.display());
+                summary.restored.push(path.clone());
+            }
+            Entry::Created { path } if unchanged(path) => {
+                if path.exists() {
+                    fs::remove_file(path).map_err(Error::io(path))?;
+                    info!("Removed {}", path.display());
+                    summary.removed.push(path.clone());
+                }
+            }
+            Entry::Saved { path, .. } | Entry::Created { path } => {
+                warn!("{} was modified since the run, leaving it", path.display());
+                summary.skipped.push(path.clone());
+            }
+            Entry::Moved { from, to } if to.exists() && !from.exists() => {
+                fs::rename(to, from).map_err(Error::io(to))?;
+                info!("Moved back {} -> {}", to.display(), from.display());
+                summary.moved_back.push(from.clone());
+            }
+            Entry::Moved { from, .. } => {
+                warn!("{} can't be moved back, leaving it", from.display());
+                summary.skipped.push(from.clone());
+            }
+        }
+    }
+    let mut undone = run_dir.clone().into_os_string();
+    undone.push(UNDONE_SUFFIX);
+    fs::rename(&run_dir, &undone).map_err(Error::io(&run_dir))?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_undo_last_run() {
+        let dir = std::env::temp_dir().join(format!(
+            "org-readwise-rust-test-backup-{}",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let settings = BackupSettings {
+            enabled: true,
+            dir: dir.join("backups"),
+            keep_runs: 2,
+        };
+        let edited = dir.join("edited.org");
+        let created = dir.join("created.org");
+        let modified_since = dir.join("modified-since.org");
+        fs::write(&edited, "before").unwrap();
+        fs::write(&modified_since, "before").unwrap();
+
+        let mut backup = Backup::start(&settings).unwrap();
+        for (path, content) in [
+            (&edited, "after"),
+            (&created, "new"),
+            (&modified_since, "after"),
+        ] {
+            backup.save(path).unwrap();
+            write_atomically(path, content.as_bytes()).unwrap();
+            backup.written(path, content).unwrap();
+        }
+        fs::write(&modified_since, "edited by hand").unwrap();
+
+        let summary = undo_last_run(&settings).unwrap();
+        assert_eq!(fs::read_to_string(&edited).unwrap(), "before");
+        assert!(!created.exists());
+        assert_eq!(
+            fs::read_to_string(&modified_since).unwrap(),
+            "edited by hand"
+        );
+        assert_eq!(summary.restored, vec![edited.clone()]);
+        assert_eq!(summary.removed, vec![created.clone()]);
+        assert_eq!(summary.skipped, vec![modified_since.clone()]);
+        // A run is only undone once
+        assert!(undo_last_run(&settings).is_err());
+
+        // Only the last keep_runs runs are kept
+        for _ in 0..3 {
+            let mut backup = Backup::start(&settings).unwrap();
+            backup.save(&edited).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        assert_eq!(runs(&settings.dir).unwrap().len(), 2);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,229 @@
+use crate::error::{Error, Result};
+use crate::util::write_atomically;
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+// On-disk copy of the items returned by the Reader API: one JSONL file per category in the cache directory
+// (each line being an item exactly as the API returned it), plus a metadata file recording when each
+// category was last fetched in full. Other tools can read these files directly.
+pub struct Cache {
+    dir: PathBuf,
+    // Merges aren't saved, e.g. for a dry run
+    read_only: bool,
+}
+
+// What changed in a category after merging freshly fetched items into the cache
+pub struct MergeResult {
+    // All the items of the category, most recently updated first (like the API returns them)
+    pub items: Vec<Value>,
+    // Items that are new or differ from their cached version
+    pub changed: Vec<Value>,
+    // Cached items that disappeared upstream (only known after a full fetch)
+    pub removed: Vec<Value>,
+}
+
+fn item_id(item: &Value) -> Option<&str> {
+    item.get("id").and_then(|id| id.as_str())
+}
+
+fn updated_at(item: &Value) -> &str {
+    item.get("updated_at")
+        .and_then(|u| u.as_str())
+        .unwrap_or("")
+}
+
+fn sorted(items: BTreeMap<String, Value>) -> Vec<Value> {
+    let mut items: Vec<Value> = items.into_values().collect();
+    items.sort_by(|a, b| {
+        updated_at(b)
+            .cmp(updated_at(a))
+            .then_with(|| item_id(b).cmp(&item_id(a)))
+    });
+    items
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            read_only: false,
+        }
+    }
+
+    pub fn read_only(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            read_only: true,
+        }
+    }
+
+    fn items_path(&self, category: &str) -> PathBuf {
+        self.dir.join(format!("{}.jsonl", category))
+    }
+
+    fn meta_path(&self) -> PathBuf {
+        self.dir.join("meta.json")
+    }
+
+    pub fn load(&self, category: &str) -> Result<BTreeMap<String, Value>> {
+        // Return the cached items of a category by id, or nothing if it was never cached
+        let path = self.items_path(category);
+        if !path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let mut items = BTreeMap::new();
+        let contents = fs::read_to_string(&path).map_err(Error::io(&path))?;
+        for (i, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let item: Value = serde_json::from_str(line).map_err(|e| {
+                Error::Parse(format!(
+                    "Invalid item at {}:{}: {}",
+                    path.display(),
+                    i + 1,
+                    e
+                ))
+            })?;
+            if let Some(id) = item_id(&item) {
+                items.insert(id.to_string(), item);
+            }
+        }
+        Ok(items)
+    }
+
+    fn save(&self, category: &str, items: &[Value]) -> Result<()> {
+        // Atomically, since it's the only full copy of the highlights and notes: a save cut short would
+        // otherwise lose items while the metadata still records a full fetch
+        fs::create_dir_all(&self.dir).map_err(Error::io(&self.dir))?;
+        let mut contents = String::new();
+        for item in items {
+            contents.push_str(&serde_json::to_string(item)?);
+            contents.push('\n');
+        }
+        write_atomically(&self.items_path(category), contents)
+    }
+
+    fn load_meta(&self) -> HashMap<String, DateTime<Utc>> {
+        fs::read_to_string(self.meta_path())
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn items(&self, category: &str) -> Result<Vec<Value>> {
+        // Return the cached items of a category, most recently updated first
+        Ok(sorted(self.load(category)?))
+    }
+
+    pub fn last_full_fetch(&self, category: &str) -> Option<DateTime<Utc>> {
+        self.load_meta().get(category).copied()
+    }
+
+    pub fn merge(&self, category: &str, fetched: Vec<Value>, full: bool) -> Result<MergeResult> {
+        // Merge freshly fetched items into the cached ones and save the result.
+        // After a full fetch, cached items that weren't fetched are removed.
+        let mut items = self.load(category)?;
+        let mut changed = Vec::new();
+        let mut fetched_ids = HashSet::new();
+        for item in fetched {
+            let Some(id) = item_id(&item).map(String::from) else {
+                continue;
+            };
+            fetched_ids.insert(id.clone());
+            match items.get(&id) {
+                Some(cached) if cached == &item => continue,
+                // Never replace an item by an older version of itself
+                Some(cached) if updated_at(cached) > updated_at(&item) => continue,
+                _ => {}
+            }
+            changed.push(item.clone());
+            items.insert(id, item);
+        }
+        let mut removed = Vec::new();
+        if full {
+            items.retain(|id, item| {
+                let keep = fetched_ids.contains(id);
+                if !keep {
+                    removed.push(item.clone());
+                }
+                keep
+            });
+        }
+
+        let items = sorted(items);
+        if !self.read_only {
+            self.save(category, &items)?;
+        }
+
+        if full && !self.read_only {
+            let mut meta = self.load_meta();
+            meta.insert(category.to_string(), Utc::now());
+            write_atomically(&self.meta_path(), serde_json::to_string_pretty(&meta)?)?;
+        }
+        Ok(MergeResult {
+            items,
+            changed,
+            removed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn test_cache(name: &str) -> Cache {
+        let dir = std::env::temp_dir().join(format!(
+            "org-readwise-rust-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        Cache::new(dir)
+    }
+
+    #[test]
+    fn test_merge() {
+        let cache = test_cache("merge");
+        let a = json!({"id": "a", "updated_at": "2024-12-01T00:00:00Z", "content": "A"});
+        let b = json!({"id": "b", "updated_at": "2024-12-02T00:00:00Z", "content": "B"});
+        let result = cache
+            .merge("highlight", vec![a.clone(), b.clone()], true)
+            .unwrap();
+        assert_eq!(result.items, vec![b.clone(), a.clone()]);
+        assert_eq!(result.changed.len(), 2);
+        assert!(cache.last_full_fetch("highlight").is_some());
+
+        // An incremental fetch updates items, but never replaces them by an older version
+        let new_a = json!({"id": "a", "updated_at": "2024-12-03T00:00:00Z", "content": "A2"});
+        let old_b = json!({"id": "b", "updated_at": "2024-11-01T00:00:00Z", "content": "B0"});
+        let result = cache
+            .merge("highlight", vec![new_a.clone(), old_b], false)
+            .unwrap();
+        assert_eq!(result.items, vec![new_a.clone(), b.clone()]);
+        assert_eq!(result.changed, vec![new_a.clone()]);
+        assert!(result.removed.is_empty());
+
+        // A full fetch removes the items that disappeared upstream
+        let result = cache.merge("highlight", vec![b.clone()], true).unwrap();
+        assert_eq!(result.items, vec![b.clone()]);
+        assert!(result.changed.is_empty());
+        assert_eq!(result.removed, vec![new_a]);
+        assert_eq!(cache.load("highlight").unwrap().len(), 1);
+        // Saved atomically, without leaving temporary files behind
+        let mut files: Vec<_> = fs::read_dir(&cache.dir)
+            .unwrap()
+            .flatten()
+            .map(|e| e.file_name())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["highlight.jsonl", "meta.json"]);
+        fs::remove_dir_all(&cache.dir).ok();
+    }
+}
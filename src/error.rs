@@ -0,0 +1,61 @@
+use std::path::{Path, PathBuf};
+
+// Everything that can go wrong during a run
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    // The Reader API couldn't be reached, or answered with an error
+    #[error("Reader API error: {0}")]
+    Api(String),
+    // An API item, a cached item or a saved date isn't what we expect
+    #[error("{0}")]
+    Parse(String),
+    #[error("{}: {error}", path.display())]
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[error("Template error: {0}")]
+    Template(#[from] tera::Error),
+    #[error("Configuration error: {0}")]
+    Config(String),
+    // The file has unsaved changes in Emacs, it's left alone until they're saved
+    #[error("{} is being edited in Emacs ({} exists)", path.display(), lock.display())]
+    OpenInEmacs { path: PathBuf, lock: PathBuf },
+    // A git command failed, or a file can't be committed
+    #[error("Git error: {0}")]
+    Git(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub fn io(path: impl AsRef<Path>) -> impl FnOnce(std::io::Error) -> Self {
+        // For use with map_err, to know which file an IO error is about
+        let path = path.as_ref().to_path_buf();
+        move |error| Error::Io { path, error }
+    }
+
+    pub fn details(&self) -> String {
+        // The message of this error, followed by the messages of its sources (tera errors only say which
+        // template failed in their own message)
+        let mut message = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source {
+            message.push_str(&format!(": {}", error));
+            source = error.source();
+        }
+        message
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::Api(error.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Parse(error.to_string())
+    }
+}
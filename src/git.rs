@@ -0,0 +1,145 @@
+use crate::error::{Error, Result};
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Commits the files a run created or edited in the org-roam directory, when it's in a git repository.
+// The files that already had uncommitted changes before the run are refused, so that a commit never
+// mixes your own edits with those from Readwise.
+pub struct AutoCommit {
+    org_roam_dir: PathBuf,
+    // The same directory, as git sees it (e.g. with symlinks resolved)
+    repo_dir: PathBuf,
+    // Files with uncommitted changes (or untracked) when the run started
+    dirty: HashSet<PathBuf>,
+    paths: Vec<PathBuf>,
+    lines: Vec<String>,
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(|e| Error::Git(format!("Failed to run git: {}", e)))?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "git {} failed ({}): {}",
+            args.first().unwrap_or(&""),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn with_paths<'a>(args: &[&'a str], paths: &[&'a str]) -> Vec<&'a str> {
+    // Limit a git command to these paths
+    let mut args = args.to_vec();
+    args.push("--");
+    args.extend(paths);
+    args
+}
+
+pub fn repo_root(dir: &Path) -> Result<PathBuf> {
+    let root = git(dir, &["rev-parse", "--show-toplevel"])
+        .map_err(|_| Error::Git(format!("{} isn't in a git repository", dir.display())))?;
+    Ok(PathBuf::from(root.trim_end()))
+}
+
+impl AutoCommit {
+    pub fn start(org_roam_dir: &Path) -> Result<Self> {
+        let root = repo_root(org_roam_dir)?;
+        let repo_dir = org_roam_dir
+            .canonicalize()
+            .map_err(Error::io(org_roam_dir))?;
+        // NUL-separated "XY path" entries, where renames and copies are followed by their source path
+        let status = git(
+            &repo_dir,
+            &["status", "--porcelain=v1", "-z", "--untracked-files=all"],
+        )?;
+        let mut dirty = HashSet::new();
+        let mut entries = status.split('\0').filter(|e| !e.is_empty());
+        while let Some(entry) = entries.next() {
+            let (code, path) = entry.split_at(entry.len().min(3));
+            dirty.insert(root.join(path));
+            if code.starts_with(['R', 'C']) {
+                entries.next();
+            }
+        }
+        Ok(Self {
+            org_roam_dir: org_roam_dir.to_path_buf(),
+            repo_dir,
+            dirty,
+            paths: Vec::new(),
+            lines: Vec::new(),
+        })
+    }
+
+    fn repo_path(&self, path: &Path) -> Option<PathBuf> {
+        // Only the files of the org-roam directory are committed, not the state files of the run
+        path.strip_prefix(&self.org_roam_dir)
+            .ok()
+            .map(|relative| self.repo_dir.join(relative))
+    }
+
+    pub fn check(&self, path: &Path) -> Result<()> {
+        // Fail if the file had uncommitted changes before the run
+        match self.repo_path(path) {
+            Some(repo_path) if self.dirty.contains(&repo_path) => Err(Error::Git(format!(
+                "{} has uncommitted changes, commit or discard them before the next sync",
+                path.display()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn add(&mut self, path: &Path) {
+        if let Some(repo_path) = self.repo_path(path) {
+            if !self.paths.contains(&repo_path) {
+                self.paths.push(repo_path);
+            }
+        }
+    }
+
+    pub fn adopt(&mut self, path: &Path) {
+        // Commit a file left uncommitted by an interrupted run with this one. Only for a file that still
+        // has what that run wrote to it, so that its uncommitted changes are ours.
+        if let Some(repo_path) = self.repo_path(path) {
+            if self.dirty.remove(&repo_path) {
+                self.paths.push(repo_path);
+            }
+        }
+    }
+
+    pub fn describe(&mut self, line: String) {
+        // A line of the body of the commit message
+        self.lines.push(line);
+    }
+
+    pub fn commit(&self, subject: &str) -> Result<Option<String>> {
+        // Stage and commit exactly the files of this run, leaving anything else staged as it was.
+        // Return the id of the commit, or None if the run didn't change any file.
+        if self.paths.is_empty() {
+            return Ok(None);
+        }
+        let paths: Vec<&str> = self.paths.iter().filter_map(|p| p.to_str()).collect();
+        git(&self.repo_dir, &with_paths(&["add", "--all"], &paths))?;
+        let changed = git(
+            &self.repo_dir,
+            &with_paths(&["diff", "--cached", "--name-only"], &paths),
+        )?;
+        if changed.trim().is_empty() {
+            return Ok(None);
+        }
+        let message = format!("{}\n\n{}\n", subject, self.lines.join("\n"));
+        git(
+            &self.repo_dir,
+            &with_paths(&["commit", "--quiet", "-m", &message], &paths),
+        )?;
+        let id = git(&self.repo_dir, &["rev-parse", "--short", "HEAD"])?;
+        Ok(Some(id.trim().to_string()))
+    }
+}
@@ -0,0 +1,38 @@
+use crate::error::{Error, Result};
+use crate::settings::HttpSettings;
+
+use reqwest::{Certificate, Client, NoProxy, Proxy};
+use std::time::Duration;
+
+pub fn build_client(settings: &HttpSettings) -> Result<Client> {
+    // Build the client shared by all the requests to the Reader API, from the [http] section of the config
+    let mut builder = Client::builder()
+        .user_agent(&settings.user_agent)
+        .connect_timeout(Duration::from_secs(settings.connect_timeout_secs))
+        .read_timeout(Duration::from_secs(settings.read_timeout_secs));
+    if let Some(proxy_url) = &settings.proxy {
+        // Hosts listed in the NO_PROXY environment variable still bypass the proxy
+        let proxy = Proxy::all(proxy_url)
+            .map_err(|e| Error::Config(format!("Invalid proxy {:?}: {}", proxy_url, e)))?
+            .no_proxy(NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+    for path in &settings.ca_certificates {
+        let pem = std::fs::read(path).map_err(Error::io(path))?;
+        let certificates = Certificate::from_pem_bundle(&pem).map_err(|e| {
+            Error::Config(format!("Invalid certificate in {}: {}", path.display(), e))
+        })?;
+        if certificates.is_empty() {
+            return Err(Error::Config(format!(
+                "No certificate found in {}",
+                path.display()
+            )));
+        }
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    builder
+        .build()
+        .map_err(|e| Error::Config(format!("Failed to build the HTTP client: {}", e)))
+}
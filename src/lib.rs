@@ -0,0 +1,29 @@
+// Sync a Readwise library (documents, highlights and notes) to the org files of an org-roam directory.
+//
+// A `Syncer` is built from explicit `Settings` (e.g. `Settings::load` for the config directory, or
+// `Settings::from_toml` for a config held in memory), and can be given its own `ReadwiseClient` and a
+// root directory for the relative paths of its settings.
+
+mod backup;
+mod cache;
+pub mod error;
+mod git;
+mod http;
+mod org;
+mod rate_limit;
+mod readwise_api;
+mod retry;
+mod roam_refs;
+pub mod settings;
+mod state;
+mod syncer;
+mod token;
+mod util;
+
+pub use backup::UndoSummary;
+pub use error::{Error, Result};
+pub use readwise_api::{ParseFailure, ReadwiseClient};
+pub use settings::Settings;
+pub use state::LastRun;
+pub use syncer::{Check, DocumentFailure, ListedDocument, RunOptions, RunSummary, Status, Syncer};
+pub use token::Token;
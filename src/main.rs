@@ -1,261 +1,338 @@
-mod readwise_api;
-mod settings;
-mod util;
+use clap::{ArgAction, Parser, Subcommand};
+use log::{info, Level, LevelFilter, Log, Metadata, Record};
+use org_readwise_rust::{settings, Error, Result, RunOptions, RunSummary, Settings, Syncer};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-use chrono::{SecondsFormat, Utc};
-use readwise_api::*;
-use settings::SETTINGS;
-use std::collections::HashMap;
-use std::path::Path;
-use std::process::Command;
-use tera::{Context, Tera};
+// Exit codes, so that systemd (or any caller) can tell a partial success from a failure
+const EXIT_FAILURE: u8 = 1;
+const EXIT_PARTIAL_SUCCESS: u8 = 2;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let start_time = std::time::Instant::now();
-    let tera = Tera::new(&SETTINGS.templates_dir.to_string_lossy())?;
-    let org_roam_dir = &SETTINGS.org_roam_dir;
-    let existing_refs = get_existing_refs(org_roam_dir)?;
-    let last_updated_after = get_updated_after().unwrap();
-    let next_updated_after = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
-    let documents = get_document_list(last_updated_after.as_deref()).await?;
-    if documents.is_empty() {
-        println!("No documents found to process. Exiting.");
-        return Ok(());
-    }
-    let highlights = get_highlight_list().await?;
-    let notes = get_note_list().await?;
-
-    let highlights_by_parent = map_parents_to_highlights(documents.clone(), highlights);
-    let notes_by_parent = note_list_to_map(notes);
+/// Sync your Readwise library to an org-roam directory
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Path of the config file [default: ~/.config/org-readwise-rust/config.toml]
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Only use this profile of the config file, instead of each of them in turn
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    /// Fail if any item fails to parse, before any file is written (sync and render)
+    #[arg(long, global = true)]
+    strict: bool,
+    /// Write nothing, print a diff of each file that would be created or edited instead (sync and render)
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// Print more details, such as each request (-vv for even more)
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Only print warnings, errors and results
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    let duplicate_titles = get_duplicate_titles(&documents);
-    println!("Duplicate titles: {:?}", duplicate_titles);
+#[derive(Subcommand)]
+enum Command {
+    /// Fetch what changed since the last sync, and create or edit the org files (the default)
+    Sync,
+    /// Show where the next sync starts from, the last run, and how many items changed since
+    Status,
+    /// Make the next sync fetch everything, or everything updated after a date
+    ResetCursor {
+        /// e.g. 2024-12-01 or 2024-12-01T10:00:00Z
+        #[arg(long)]
+        to: Option<String>,
+    },
+    /// Rebuild the org files from the local copy with the current templates, without network calls
+    #[command(alias = "rerender")]
+    Render {
+        /// Only render these documents [default: all of them]
+        document_ids: Vec<String>,
+    },
+    /// List the documents of the local copy, with the file written for each of them
+    List,
+    /// Check the configuration, the directories, the API token and the connection to the Reader API
+    Doctor,
+    /// Restore the files modified by the last sync or render from their backups
+    UndoLastRun,
+}
 
-    let mut files_created = 0;
-    let mut files_edited = 0;
-    for parent_id in highlights_by_parent.keys().cloned() {
-        // Find the parent document
-        let parent = documents
-            .iter()
-            .find(|d| d.id == parent_id)
-            .expect("Parent document must exist since we got its ID from highlights_by_parent");
+// Prints the messages of the library, up to the level set by --verbose or --quiet: progress to stdout,
+// warnings and errors to stderr
+struct Logger;
 
-        let highlights_with_notes =
-            get_highlights_with_notes(&highlights_by_parent, &notes_by_parent, &parent_id);
+static LOGGER: Logger = Logger;
 
-        let highlight_content = generate_highlight_content(&highlights_with_notes, &tera)?;
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level() && metadata.target().starts_with("org_readwise_rust")
+    }
 
-        if existing_refs.contains_key(&parent.roam_ref) {
-            let filename = existing_refs[&parent.roam_ref].clone();
-            edit_file(&filename, parent, &highlight_content);
-            println!("Edited file: {}", filename);
-            files_edited += 1;
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= Level::Warn {
+            eprintln!("{}", record.args());
         } else {
-            let filename = if duplicate_titles.contains(&parent.title) {
-                get_new_entry_filename(org_roam_dir, &parent.title, Some(&parent.source_url))
-            } else {
-                get_new_entry_filename(org_roam_dir, &parent.title, None)
-            };
-
-            let content = generate_file_content(parent, &highlight_content, &tera)?;
-            std::fs::write(&filename, &content)?;
-            println!("Created file: {}", filename);
-            files_created += 1;
+            println!("{}", record.args());
         }
     }
-    println!("\nCreated {} files", files_created);
-    println!("Edited {} files", files_edited);
-    // Only save this if everything went well. If the program crashes in the middle, the next run will still use the old updated_after date and no update from readwise will be lost.
-    println!("Saving next updated_after date: {}", next_updated_after);
-    save_updated_after(&next_updated_after);
-    let duration = start_time.elapsed();
-    println!("Time taken: {:?}", duration);
-    Ok(())
-}
 
-fn get_existing_refs(
-    org_roam_dir: &Path,
-) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-    // Run ripgrep to find all ROAM_REFS lines in org_roam_dir.
-    // Return a mapping from roam_ref to full filename.
-    let output = Command::new("rg")
-        .args([
-            "--with-filename",
-            "^:ROAM_REFS:",
-            &org_roam_dir.to_string_lossy(),
-        ])
-        .output()?;
+    fn flush(&self) {}
+}
 
-    let output_str = String::from_utf8(output.stdout)?;
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let level = match (cli.quiet, cli.verbose) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    log::set_logger(&LOGGER).ok();
+    log::set_max_level(level);
 
-    // Parse the output into a map of roam_ref -> filename
-    let mut refs_map = HashMap::new();
-    for line in output_str.lines() {
-        // Each line is in the format: filename::ROAM_REFS: ref
-        if let Some((filename, roam_ref)) = line.split_once("::ROAM_REFS:") {
-            let roam_ref = roam_ref.trim().to_string();
-            refs_map.insert(roam_ref, filename.to_string());
-        }
+    let config_path = match cli
+        .config
+        .clone()
+        .map_or_else(settings::default_config_path, Ok)
+    {
+        Ok(path) => path,
+        Err(e) => return fail(e),
+    };
+    if cli.profile.is_some() {
+        return run(&cli, &config_path, cli.profile.as_deref()).await;
+    }
+    // Without --profile, every profile defined in the config file is used in turn, or the top-level
+    // settings if there are none
+    match settings::profile_names(&config_path) {
+        Ok(names) if names.is_empty() => run(&cli, &config_path, None).await,
+        Ok(names) => run_profiles(&cli, &config_path, &names).await,
+        Err(e) => fail(e),
     }
-    Ok(refs_map)
 }
 
-fn get_new_entry_filename(org_roam_dir: &Path, title: &str, url: Option<&str>) -> String {
-    // Generate a new filename for a new org-roam entry, based on the title.
-    // If the URL is provided, also include the first 8 characters of the MD5 hash of the URL in the filename.
-    let now = chrono::Local::now();
-    let slug = slug::slugify(title);
-    let truncated_slug = if slug.len() > 100 {
-        slug[..100].to_string()
-    } else {
-        slug
-    };
+fn fail(error: Error) -> ExitCode {
+    eprintln!("Error: {}", error.details());
+    ExitCode::from(EXIT_FAILURE)
+}
 
-    let maybe_url_part = if let Some(u) = url {
-        let hash = md5::compute(u);
-        let hash_str = format!("{:08x}", hash);
-        let truncated_hash = &hash_str[..8];
-        format!("-{}", truncated_hash)
+async fn run_profiles(cli: &Cli, config_path: &Path, names: &[String]) -> ExitCode {
+    // Run each profile in turn. The exit code is that of the profiles if they agree, otherwise
+    // a partial success.
+    let mut codes = Vec::new();
+    for name in names {
+        println!("=== Profile {} ===", name);
+        let code = run(cli, config_path, Some(name)).await;
+        if code != ExitCode::SUCCESS {
+            eprintln!("Profile {} didn't complete", name);
+        }
+        codes.push(code);
+    }
+    if codes.iter().all(|c| *c == ExitCode::SUCCESS) {
+        ExitCode::SUCCESS
+    } else if codes.iter().all(|c| *c == ExitCode::from(EXIT_FAILURE)) {
+        ExitCode::from(EXIT_FAILURE)
     } else {
-        String::new()
-    };
-    org_roam_dir
-        .join(format!(
-            "{}-{}{}.org",
-            now.format("%Y%m%d%H%M%S"),
-            truncated_slug,
-            maybe_url_part
-        ))
-        .to_string_lossy()
-        .into_owned()
+        ExitCode::from(EXIT_PARTIAL_SUCCESS)
+    }
 }
 
-fn get_duplicate_titles(documents: &[Document]) -> Vec<String> {
-    // Return a list of titles that appear more than once in the document list
-    let mut title_counts: HashMap<String, u32> = HashMap::new();
-    for document in documents {
-        *title_counts.entry(document.title.clone()).or_default() += 1;
+async fn run(cli: &Cli, config_path: &Path, profile: Option<&str>) -> ExitCode {
+    let syncer = match Settings::load(config_path, profile) {
+        Ok(settings) => Syncer::new(settings),
+        Err(e) => return fail(e),
+    };
+    let options = RunOptions {
+        strict: cli.strict,
+        dry_run: cli.dry_run,
+    };
+    let result = match cli.command.as_ref().unwrap_or(&Command::Sync) {
+        Command::Sync => timed(syncer.sync(options)).await,
+        Command::Render { document_ids } => {
+            timed(async { syncer.render(document_ids, options) }).await
+        }
+        Command::Status => return status(&syncer).await,
+        Command::ResetCursor { to } => return reset_cursor(&syncer, to.as_deref()),
+        Command::List => return list(&syncer),
+        Command::Doctor => return doctor(&syncer).await,
+        Command::UndoLastRun => return undo_last_run(&syncer),
+    };
+    match result {
+        Ok(summary) => {
+            print_summary(&summary);
+            if summary.failed.is_empty() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::from(EXIT_PARTIAL_SUCCESS)
+            }
+        }
+        Err(e) => fail(e),
     }
-    title_counts
-        .iter()
-        .filter(|(_, count)| **count > 1)
-        .map(|(title, _)| title.clone())
-        .collect()
 }
 
-fn get_highlights_with_notes(
-    highlights_by_parent: &HashMap<String, Vec<Highlight>>,
-    notes_by_parent: &HashMap<String, Note>,
-    parent_id: &str,
-) -> Vec<serde_json::Value> {
-    let highlights = highlights_by_parent.get(parent_id).unwrap();
-    highlights
-        .iter()
-        .rev() // Reverse the order of highlights so they end up in the correct order in the org file
-        .map(|highlight| {
-            let note = notes_by_parent.get(&highlight.id);
-            serde_json::json!({
-                "id": highlight.id,
-                "content": highlight.content,
-                "note": note.map(|n| n.content.clone()),
-                "note_saved_at": note.map(|n| {
-                    chrono::DateTime::parse_from_rfc3339(&n.saved_at)
-                        .map(|dt| dt.format("%Y-%m-%d").to_string())
-                        .unwrap()
-                }),
-            })
-        })
-        .collect()
+async fn timed(run: impl std::future::Future<Output = Result<RunSummary>>) -> Result<RunSummary> {
+    let start_time = std::time::Instant::now();
+    let result = run.await;
+    info!("Time taken: {:?}", start_time.elapsed());
+    result
 }
 
-fn generate_highlight_content(
-    highlights_with_notes: &Vec<serde_json::Value>,
-    tera: &Tera,
-) -> Result<String, tera::Error> {
-    // Generate the highlight and note section as a string
-    if highlights_with_notes.is_empty() {
-        return Ok(String::new());
+fn print_summary(summary: &RunSummary) {
+    for change in &summary.changes {
+        print!("{}", change);
+    }
+    if let Some(date) = &summary.next_updated_after {
+        println!("Would save next updated_after date: {}", date);
+    }
+    println!();
+    if summary.dry_run {
+        println!("Dry run, nothing was written");
+    }
+    println!("Created {} files", summary.files_created);
+    println!("Edited {} files", summary.files_edited);
+    if summary.files_skipped > 0 {
+        println!(
+            "Skipped {} files already written by an interrupted sync",
+            summary.files_skipped
+        );
+    }
+    if !summary.parse_failures.is_empty() {
+        println!(
+            "{} items failed to parse and were skipped:",
+            summary.parse_failures.len()
+        );
+        for failure in &summary.parse_failures {
+            println!("- {}", failure);
+        }
+    }
+    if !summary.deferred.is_empty() {
+        println!(
+            "{} documents deferred until their file is saved in Emacs:",
+            summary.deferred.len()
+        );
+        for deferral in &summary.deferred {
+            println!("- {} ({}): {}", deferral.title, deferral.id, deferral.error);
+        }
+    }
+    if !summary.failed.is_empty() {
+        println!("{} documents failed:", summary.failed.len());
+        for failure in &summary.failed {
+            println!(
+                "- {} ({}): {}",
+                failure.title,
+                failure.id,
+                failure.error.details()
+            );
+        }
     }
-    let mut highlight_context = Context::new();
-    highlight_context.insert("highlights", highlights_with_notes);
-    tera.render("highlights.tera", &highlight_context)
 }
 
-fn generate_file_content(
-    document: &Document,
-    highlight_content: &str,
-    tera: &Tera,
-) -> Result<String, tera::Error> {
-    let uuid = uuid::Uuid::new_v4().to_string();
-
-    let mut context = Context::new();
-    context.insert("uuid", &uuid);
-    context.insert("roam_ref", &document.roam_ref);
-    if document.has_url {
-        context.insert("full_url", &document.source_url);
+async fn status(syncer: &Syncer) -> ExitCode {
+    let status = match syncer.status().await {
+        Ok(status) => status,
+        Err(e) => return fail(e),
+    };
+    match &status.updated_after {
+        Some(date) => println!("Next sync fetches what was updated after {}", date),
+        None => println!("Next sync fetches everything"),
     }
-    context.insert("readwise_url", &document.readwise_url);
-    context.insert("title", &document.title);
-    context.insert("author", &document.author);
-    context.insert(
-        "saved_at",
-        &document.saved_at.format("%Y-%m-%d %a").to_string(),
-    );
-    if let Some(published_date) = document.published_date {
-        context.insert(
-            "published_date",
-            &published_date.format("%Y-%m-%d").to_string(),
+    if let Some(written) = status.interrupted_sync {
+        println!(
+            "The last sync didn't complete, {} documents were already written",
+            written
         );
     }
-    context.insert(
-        "read_status",
-        read_status_by_location(document.location.as_str()),
-    );
-    context.insert("highlight_content", highlight_content);
-    tera.render("document.org.tera", &context)
+    match &status.last_run {
+        Some(run) => println!(
+            "Last run: {} at {}, {} files created, {} edited, {} documents failed, {} items failed to parse",
+            run.command,
+            run.finished_at.to_rfc3339(),
+            run.files_created,
+            run.files_edited,
+            run.documents_failed,
+            run.parse_failures
+        ),
+        None => println!("Last run: never"),
+    }
+    println!("Synced documents: {}", status.synced_documents);
+    match status.pending {
+        Ok(pending) => {
+            println!("Updated upstream since then:");
+            for (category, count) in pending {
+                println!("- {}: {}", category, count);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => fail(e),
+    }
 }
 
-fn edit_file(filename: &str, parent: &Document, highlight_content: &str) {
-    // Read all lines from file
-    let content = std::fs::read_to_string(filename).expect("Failed to read file");
-    let lines: Vec<_> = content.lines().collect();
-
-    // Find index where highlights section starts
-    let highlight_index = lines
-        .iter()
-        .position(|line| line.trim() == "* readwise:highlights")
-        .unwrap_or(lines.len());
-
-    // Update read status
-    let mut updated_lines = lines[..highlight_index].to_vec();
-
-    let read_status_line = format!(
-        "- read status: {}",
-        read_status_by_location(parent.location.as_str())
-    );
-    if let Some(pos) = updated_lines
-        .iter()
-        .position(|line| line.trim().starts_with("- read status:"))
-    {
-        updated_lines[pos] = read_status_line.as_str();
+fn reset_cursor(syncer: &Syncer, to: Option<&str>) -> ExitCode {
+    if let Err(e) = syncer.reset_cursor(to) {
+        return fail(e);
     }
+    match to {
+        Some(date) => println!("Next sync fetches what was updated after {}", date),
+        None => println!("Next sync fetches everything"),
+    }
+    ExitCode::SUCCESS
+}
 
-    // Keep everything before highlights section with updated read status
-    let mut new_content = updated_lines.join("\n");
-
-    // Add the new highlight content
-    new_content.push('\n');
-    new_content.push_str(highlight_content);
-
-    // Write back to file
-    std::fs::write(filename, new_content).expect("Failed to write file");
+fn list(syncer: &Syncer) -> ExitCode {
+    let documents = match syncer.list() {
+        Ok(documents) => documents,
+        Err(e) => return fail(e),
+    };
+    for document in documents {
+        println!(
+            "{}\t{}\t{}",
+            document.id,
+            document.title,
+            document.file.as_deref().unwrap_or("-")
+        );
+    }
+    ExitCode::SUCCESS
 }
 
-fn read_status_by_location(location: &str) -> &str {
-    if location == "archive" {
-        "DONE"
+async fn doctor(syncer: &Syncer) -> ExitCode {
+    let checks = syncer.doctor().await;
+    for check in &checks {
+        match &check.result {
+            Ok(detail) => println!("ok    {}: {}", check.name, detail),
+            Err(e) => println!("FAIL  {}: {}", check.name, e.details()),
+        }
+    }
+    if checks.iter().all(|c| c.result.is_ok()) {
+        ExitCode::SUCCESS
     } else {
-        "TODO"
+        ExitCode::from(EXIT_FAILURE)
+    }
+}
+
+fn undo_last_run(syncer: &Syncer) -> ExitCode {
+    let summary = match syncer.undo_last_run() {
+        Ok(summary) => summary,
+        Err(e) => return fail(e),
+    };
+    println!(
+        "Undid run {}: {} files restored, {} removed, {} moved back",
+        summary.run,
+        summary.restored.len(),
+        summary.removed.len(),
+        summary.moved_back.len()
+    );
+    if summary.skipped.is_empty() {
+        return ExitCode::SUCCESS;
+    }
+    println!("Left alone, as they were modified since:");
+    for path in &summary.skipped {
+        println!("- {}", path.display());
     }
+    ExitCode::from(EXIT_PARTIAL_SUCCESS)
 }
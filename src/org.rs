@@ -0,0 +1,846 @@
+// Minimal helpers to work on the structure of org files, line by line.
+
+use std::collections::HashMap;
+
+pub const HIGHLIGHTS_HEADING: &str = "readwise:highlights";
+
+pub fn heading_level(line: &str) -> Option<usize> {
+    // Return the level of an org heading (the number of leading stars), or None if the line isn't a heading.
+    // A heading is one or more stars at the very beginning of the line, followed by a space (or nothing).
+    let stars = line.chars().take_while(|&c| c == '*').count();
+    if stars == 0 {
+        return None;
+    }
+    match line[stars..].chars().next() {
+        None | Some(' ') | Some('\t') => Some(stars),
+        _ => None,
+    }
+}
+
+pub fn heading_title(line: &str) -> Option<&str> {
+    // Return the title of an org heading, without its stars, or None if the line isn't a heading.
+    heading_level(line).map(|level| line[level..].trim())
+}
+
+pub fn find_subtree(lines: &[&str], title: &str) -> Option<(usize, usize)> {
+    // Return the [start, end) line range of the first subtree whose heading has the given title.
+    // The subtree ends right before the next heading of the same or a higher level (fewer stars),
+    // or at the end of the file.
+    let (start, level) = lines.iter().enumerate().find_map(|(i, line)| {
+        match (heading_level(line), heading_title(line)) {
+            (Some(level), Some(t)) if t == title => Some((i, level)),
+            _ => None,
+        }
+    })?;
+    let end = lines[start + 1..]
+        .iter()
+        .position(|line| heading_level(line).is_some_and(|l| l <= level))
+        .map(|offset| start + 1 + offset)
+        .unwrap_or(lines.len());
+    Some((start, end))
+}
+
+pub fn replace_subtree(content: &str, title: &str, replacement: &str) -> String {
+    // Replace the subtree with the given heading title by `replacement`, preserving everything before
+    // and after it. If there is no such subtree, `replacement` is appended at the end of the file.
+    let lines: Vec<&str> = content.lines().collect();
+    let (start, end) = find_subtree(&lines, title).unwrap_or((lines.len(), lines.len()));
+
+    let mut new_content = lines[..start].join("\n");
+    if start > 0 {
+        new_content.push('\n');
+    }
+    new_content.push_str(replacement);
+
+    let rest = &lines[end..];
+    if !rest.is_empty() {
+        if !new_content.ends_with('\n') {
+            new_content.push('\n');
+        }
+        new_content.push_str(&rest.join("\n"));
+        if content.ends_with('\n') {
+            new_content.push('\n');
+        }
+    }
+    new_content
+}
+
+pub const ORPHANS_HEADING: &str = "readwise:orphaned-annotations";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Annotations {
+    // Paragraphs and list items written directly under the highlight heading
+    pub body: Vec<String>,
+    // Lines written under the note heading, other than the note text
+    pub note: Vec<String>,
+    // Child headings (other than the note heading) with their content
+    pub children: Vec<String>,
+}
+
+impl Annotations {
+    pub fn is_empty(&self) -> bool {
+        self.body.is_empty() && self.note.is_empty() && self.children.is_empty()
+    }
+}
+
+fn trim_blank_lines(lines: &mut Vec<String>) {
+    // Blank lines only matter between annotations
+    while lines.first().is_some_and(|l| l.trim().is_empty()) {
+        lines.remove(0);
+    }
+    while lines.last().is_some_and(|l| l.trim().is_empty()) {
+        lines.pop();
+    }
+}
+
+fn is_note_heading(line: &str) -> bool {
+    heading_title(line).is_some_and(|t| t == "note" || t.starts_with("note ("))
+}
+
+pub fn highlight_blocks<'a>(lines: &[&'a str]) -> Vec<(&'a str, usize, usize)> {
+    // Return the (id, start, end) line ranges of each `readwise:<id>` heading directly under the highlights section
+    let Some((start, end)) = find_subtree(lines, HIGHLIGHTS_HEADING) else {
+        return Vec::new();
+    };
+    let level = heading_level(lines[start]).unwrap_or(1) + 1;
+    let mut blocks: Vec<(&str, usize, usize)> = Vec::new();
+    for (i, line) in lines.iter().enumerate().take(end).skip(start + 1) {
+        let Some(l) = heading_level(line) else {
+            continue;
+        };
+        if l > level {
+            continue;
+        }
+        if let Some(block) = blocks.last_mut() {
+            if block.2 == end {
+                block.2 = i;
+            }
+        }
+        if let Some(id) = heading_title(line).and_then(|t| t.strip_prefix("readwise:")) {
+            if l == level {
+                blocks.push((id, i, end));
+            }
+        }
+    }
+    blocks
+}
+
+fn is_list_item(line: &str) -> bool {
+    // Whether the line starts an org list item: `-`, `+`, or an indented `*` bullet, or `1.`/`1)`
+    let trimmed = line.trim_start();
+    let bullet = match trimmed.chars().next() {
+        Some('-') | Some('+') => Some(&trimmed[1..]),
+        Some('*') if trimmed.len() < line.len() => Some(&trimmed[1..]),
+        _ => {
+            let digits = trimmed.chars().take_while(|c| c.is_ascii_digit()).count();
+            trimmed[digits..]
+                .strip_prefix(['.', ')'])
+                .filter(|_| digits > 0)
+        }
+    };
+    bullet.is_some_and(|rest| rest.is_empty() || rest.starts_with([' ', '\t']))
+}
+
+pub fn extract_annotations(
+    block: &[&str],
+    rendered: Option<&[&str]>,
+    previously_rendered: Option<&[&str]>,
+) -> Annotations {
+    // Return what the user added inside a highlight block: anything that isn't the heading, the note
+    // heading, or a line of the rendered highlight or note, either as it's rendered now or as it was when
+    // the block was written (so that text edited upstream isn't mistaken for an annotation).
+    // Without the previous version, the first paragraph under the note heading is taken as the note
+    // text, which may have been edited upstream. When neither is known (a highlight deleted upstream
+    // before the state file recorded it), the rendered text is assumed to be made of paragraphs: only
+    // list items (with their continuation lines) and child headings are taken as annotations.
+    let mut annotations = Annotations::default();
+    let Some(level) = block.first().and_then(|line| heading_level(line)) else {
+        return annotations;
+    };
+    let mut in_note = false;
+    let mut in_child = false;
+    let mut in_list = false;
+    // Whether the first paragraph under the note heading started, and ended
+    let mut note_text = (false, false);
+    for line in &block[1..] {
+        match heading_level(line) {
+            Some(l) if l == level + 1 && is_note_heading(line) => {
+                in_note = true;
+                in_child = false;
+                in_list = false;
+                note_text = (false, false);
+            }
+            Some(_) => {
+                in_note = false;
+                in_child = true;
+                annotations.children.push(line.to_string());
+            }
+            None if in_child => annotations.children.push(line.to_string()),
+            None => {
+                if is_list_item(line) {
+                    in_list = true;
+                } else if !line.trim().is_empty() && !line.starts_with([' ', '\t']) {
+                    in_list = false;
+                }
+                if in_note {
+                    note_text = match line.trim().is_empty() {
+                        true => (note_text.0, note_text.0),
+                        false => (true, note_text.1),
+                    };
+                }
+                let matches = |lines: &[&str]| lines.iter().any(|r| r.trim() == line.trim());
+                let generated = match (rendered, previously_rendered) {
+                    (_, Some(previous)) if matches(previous) => true,
+                    (Some(rendered), _) if matches(rendered) => true,
+                    (_, Some(_)) => false,
+                    (Some(_), None) => in_note && !note_text.1 && !in_list,
+                    (None, None) => !in_list && !line.trim().is_empty(),
+                };
+                if !generated {
+                    match in_note {
+                        true => annotations.note.push(line.to_string()),
+                        false => annotations.body.push(line.to_string()),
+                    }
+                }
+            }
+        }
+    }
+    trim_blank_lines(&mut annotations.body);
+    trim_blank_lines(&mut annotations.note);
+    annotations
+}
+
+pub fn merge_annotations(
+    old_content: &str,
+    new_section: &str,
+    previous_section: Option<&str>,
+) -> (String, Vec<String>) {
+    // Re-attach the annotations found in the highlights section of `old_content` to the same highlight
+    // ids in the freshly rendered `new_section`. `previous_section` is the section as it was rendered
+    // when `old_content` was written, if known.
+    // Return the merged section, and the full blocks of annotated highlights that no longer exist upstream.
+    let old_lines: Vec<&str> = old_content.lines().collect();
+    let new_lines: Vec<&str> = new_section.lines().collect();
+    let new_blocks = highlight_blocks(&new_lines);
+    let previous_lines: Vec<&str> = previous_section.unwrap_or_default().lines().collect();
+    let previous_blocks = highlight_blocks(&previous_lines);
+    let previous_block = |id: &str| {
+        previous_blocks
+            .iter()
+            .find(|(previous_id, _, _)| *previous_id == id)
+            .map(|(_, start, end)| &previous_lines[*start..*end])
+    };
+
+    let mut old_annotations: HashMap<&str, Annotations> = HashMap::new();
+    let mut orphans = Vec::new();
+    for (id, start, end) in highlight_blocks(&old_lines) {
+        let block = &old_lines[start..end];
+        match new_blocks.iter().find(|(new_id, _, _)| *new_id == id) {
+            Some((_, new_start, new_end)) => {
+                let annotations = extract_annotations(
+                    block,
+                    Some(&new_lines[*new_start..*new_end]),
+                    previous_block(id),
+                );
+                if !annotations.is_empty() {
+                    old_annotations.insert(id, annotations);
+                }
+            }
+            None => {
+                if !extract_annotations(block, None, previous_block(id)).is_empty() {
+                    orphans.extend(block.iter().map(|l| l.trim_end().to_string()));
+                }
+            }
+        }
+    }
+    if old_annotations.is_empty() {
+        return (new_section.to_string(), orphans);
+    }
+
+    let mut merged: Vec<String> = Vec::new();
+    let mut previous_end = 0;
+    for (id, start, end) in &new_blocks {
+        merged.extend(
+            new_lines[previous_end..*start]
+                .iter()
+                .map(|l| l.to_string()),
+        );
+        previous_end = *end;
+        let block = &new_lines[*start..*end];
+        let level = heading_level(block[0]).unwrap_or(1);
+        let Some(annotations) = old_annotations.get(id) else {
+            merged.extend(block.iter().map(|l| l.to_string()));
+            continue;
+        };
+        // Body annotations go right after the highlight text, before the note heading, and note
+        // annotations right after the note text (or after the body, if the note was deleted upstream)
+        let next_heading = |from: usize| {
+            block[from..]
+                .iter()
+                .position(|line| heading_level(line).is_some())
+                .map(|p| p + from)
+                .unwrap_or(block.len())
+        };
+        let first_child = next_heading(1);
+        let note_end = block[1..]
+            .iter()
+            .position(|line| heading_level(line) == Some(level + 1) && is_note_heading(line))
+            .map(|p| next_heading(p + 2))
+            .unwrap_or(first_child);
+        merged.extend(block[..first_child].iter().map(|l| l.to_string()));
+        merged.extend(annotations.body.iter().cloned());
+        merged.extend(block[first_child..note_end].iter().map(|l| l.to_string()));
+        merged.extend(annotations.note.iter().cloned());
+        merged.extend(block[note_end..].iter().map(|l| l.to_string()));
+        merged.extend(annotations.children.iter().cloned());
+    }
+    merged.extend(new_lines[previous_end..].iter().map(|l| l.to_string()));
+    (merged.join("\n"), orphans)
+}
+
+pub fn append_orphans(content: &str, orphans: &[String]) -> String {
+    // Append highlight blocks to the orphaned annotations section, creating it after the highlights section if needed
+    append_to_section(content, ORPHANS_HEADING, ORPHANS_DESCRIPTION, orphans)
+}
+
+pub fn append_deleted(content: &str, blocks: &[String]) -> String {
+    // Append highlight blocks to the deleted highlights section, creating it after the highlights section if needed
+    append_to_section(content, DELETED_HEADING, DELETED_DESCRIPTION, blocks)
+}
+
+fn append_to_section(content: &str, title: &str, description: &str, blocks: &[String]) -> String {
+    if blocks.is_empty() {
+        return content.to_string();
+    }
+    let heading_line = format!("* {}", title);
+    let lines: Vec<&str> = content.lines().collect();
+    let (replaced_title, mut section) = match find_subtree(&lines, title) {
+        Some((start, end)) => (title, lines[start..end].to_vec()),
+        None => match find_subtree(&lines, HIGHLIGHTS_HEADING) {
+            Some((start, end)) => {
+                let mut section = lines[start..end].to_vec();
+                section.push(&heading_line);
+                section.push(description);
+                (HIGHLIGHTS_HEADING, section)
+            }
+            None => (title, vec![heading_line.as_str(), description]),
+        },
+    };
+    while section.last().is_some_and(|l| l.trim().is_empty()) {
+        section.pop();
+    }
+    section.extend(blocks.iter().map(|l| l.as_str()));
+    replace_subtree(content, replaced_title, &section.join("\n"))
+}
+
+pub fn take_highlight_blocks(content: &str, ids: &[String]) -> (String, Vec<Vec<String>>) {
+    // Remove the blocks of the given highlight ids from the highlights section.
+    // Return the remaining content, and the lines of each removed block.
+    let lines: Vec<&str> = content.lines().collect();
+    let mut removed = Vec::new();
+    let mut kept = Vec::new();
+    let mut previous_end = 0;
+    for (id, start, end) in highlight_blocks(&lines) {
+        if ids.iter().any(|i| i == id) {
+            kept.extend_from_slice(&lines[previous_end..start]);
+            removed.push(
+                lines[start..end]
+                    .iter()
+                    .map(|l| l.trim_end().to_string())
+                    .collect(),
+            );
+            previous_end = end;
+        }
+    }
+    kept.extend_from_slice(&lines[previous_end..]);
+    let mut remaining = kept.join("\n");
+    if content.ends_with('\n') {
+        remaining.push('\n');
+    }
+    (remaining, removed)
+}
+
+pub fn highlight_ids(content: &str) -> Vec<String> {
+    let lines: Vec<&str> = content.lines().collect();
+    highlight_blocks(&lines)
+        .into_iter()
+        .map(|(id, _, _)| id.to_string())
+        .collect()
+}
+
+pub fn commented_blocks(content: &str) -> Vec<String> {
+    // Return the lines of the highlight blocks that were commented out (`** COMMENT readwise:<id>`)
+    let lines: Vec<&str> = content.lines().collect();
+    let Some((start, end)) = find_subtree(&lines, HIGHLIGHTS_HEADING) else {
+        return Vec::new();
+    };
+    let level = heading_level(lines[start]).unwrap_or(1) + 1;
+    let mut blocks = Vec::new();
+    let mut in_commented_block = false;
+    for line in &lines[start + 1..end] {
+        if let Some(l) = heading_level(line) {
+            if l <= level {
+                in_commented_block =
+                    heading_title(line).is_some_and(|t| t.starts_with("COMMENT readwise:"));
+            }
+        }
+        if in_commented_block {
+            blocks.push(line.trim_end().to_string());
+        }
+    }
+    blocks
+}
+
+pub fn comment_out(block: &[String]) -> Vec<String> {
+    // Add the COMMENT keyword to the heading of a highlight block, so that org ignores it on export
+    let mut block = block.to_vec();
+    if let Some(first) = block.first_mut() {
+        if let Some(level) = heading_level(first) {
+            *first = format!("{} COMMENT {}", &first[..level], first[level..].trim());
+        }
+    }
+    block
+}
+
+pub fn add_filetag(content: &str, tag: &str) -> String {
+    // Add a tag to the `#+filetags:` line of the file, creating the line after `#+TITLE:` if needed
+    let tag_marker = format!(":{}:", tag);
+    let mut lines: Vec<String> = content.lines().map(String::from).collect();
+    if let Some(line) = lines
+        .iter_mut()
+        .find(|l| l.to_lowercase().starts_with("#+filetags:"))
+    {
+        if !line.contains(&tag_marker) {
+            if line.trim_end().ends_with(':') {
+                line.push_str(&tag_marker[1..]);
+            } else {
+                line.push(' ');
+                line.push_str(&tag_marker);
+            }
+        }
+    } else {
+        let position = lines
+            .iter()
+            .position(|l| l.to_lowercase().starts_with("#+title:"))
+            .map(|p| p + 1)
+            .unwrap_or(0);
+        lines.insert(position, format!("#+filetags: {}", tag_marker));
+    }
+    let mut new_content = lines.join("\n");
+    if content.ends_with('\n') {
+        new_content.push('\n');
+    }
+    new_content
+}
+
+const ORPHANS_DESCRIPTION: &str =
+    "Annotated highlights that were deleted in Readwise. The sync only ever appends to this section.";
+
+pub const DELETED_HEADING: &str = "readwise:deleted";
+const DELETED_DESCRIPTION: &str =
+    "Highlights that were deleted in Readwise. The sync only ever appends to this section.";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = ":PROPERTIES:
+:ID: 9950a8f8-3251-4a73-ae95-9ee5ad6362d3
+:ROAM_REFS: https://example.com
+:END:
+#+TITLE: Example
+
+- read status: TODO
+";
+
+    const NEW_HIGHLIGHTS: &str = "* readwise:highlights
+** readwise:2
+New highlight";
+
+    #[test]
+    fn test_heading_level() {
+        assert_eq!(heading_level("* readwise:highlights"), Some(1));
+        assert_eq!(heading_level("*** note (2024-12-03)"), Some(3));
+        assert_eq!(heading_level("*"), Some(1));
+        assert_eq!(heading_level("*bold* text"), None);
+        assert_eq!(heading_level(" * indented list item"), None);
+        assert_eq!(heading_level("- list item"), None);
+    }
+
+    #[test]
+    fn test_find_subtree() {
+        let lines = vec![
+            "intro",
+            "* readwise:highlights",
+            "** readwise:1",
+            "text",
+            "* My thoughts",
+            "more",
+        ];
+        assert_eq!(find_subtree(&lines, HIGHLIGHTS_HEADING), Some((1, 4)));
+        assert_eq!(find_subtree(&lines, "My thoughts"), Some((4, 6)));
+        assert_eq!(find_subtree(&lines, "missing"), None);
+    }
+
+    #[test]
+    fn test_replace_without_existing_section() {
+        let content = HEADER;
+        let result = replace_subtree(content, HIGHLIGHTS_HEADING, NEW_HIGHLIGHTS);
+        assert_eq!(
+            result,
+            format!("{}\n{}", HEADER.trim_end_matches('\n'), NEW_HIGHLIGHTS)
+        );
+    }
+
+    #[test]
+    fn test_replace_section_at_end_of_file() {
+        let content = format!(
+            "{}\n* readwise:highlights\n** readwise:1\nOld highlight\n*** note (2024-12-03)\nOld note\n",
+            HEADER
+        );
+        let result = replace_subtree(&content, HIGHLIGHTS_HEADING, NEW_HIGHLIGHTS);
+        assert_eq!(result, format!("{}\n{}", HEADER, NEW_HIGHLIGHTS));
+    }
+
+    #[test]
+    fn test_replace_preserves_content_after_section() {
+        let content = format!(
+            "{}\n* readwise:highlights\n** readwise:1\nOld highlight\n* My thoughts\nThis must survive.\n** Sub-thought\nThis too.\n",
+            HEADER
+        );
+        let result = replace_subtree(&content, HIGHLIGHTS_HEADING, NEW_HIGHLIGHTS);
+        assert_eq!(
+            result,
+            format!(
+                "{}\n{}\n* My thoughts\nThis must survive.\n** Sub-thought\nThis too.\n",
+                HEADER, NEW_HIGHLIGHTS
+            )
+        );
+    }
+
+    #[test]
+    fn test_replace_preserves_content_before_section() {
+        let content = "Intro paragraph\n* Earlier heading\nWith text\n* readwise:highlights\n** readwise:1\nOld\n";
+        let result = replace_subtree(content, HIGHLIGHTS_HEADING, NEW_HIGHLIGHTS);
+        assert_eq!(
+            result,
+            format!(
+                "Intro paragraph\n* Earlier heading\nWith text\n{}",
+                NEW_HIGHLIGHTS
+            )
+        );
+    }
+
+    #[test]
+    fn test_replace_removes_everything_inside_section() {
+        // Deeper headings and list items inside the section belong to it and are replaced
+        let content = "* readwise:highlights\n** readwise:1\nOld\n*** note (2024-12-03)\nOld note\n**** deeper\n- item\n* After\n";
+        let result = replace_subtree(content, HIGHLIGHTS_HEADING, NEW_HIGHLIGHTS);
+        assert_eq!(result, format!("{}\n* After\n", NEW_HIGHLIGHTS));
+    }
+
+    #[test]
+    fn test_replace_with_empty_highlights_keeps_content_after() {
+        let content = "header\n* readwise:highlights\n** readwise:1\nOld\n* My thoughts\nKeep\n";
+        let result = replace_subtree(content, HIGHLIGHTS_HEADING, "");
+        assert_eq!(result, "header\n* My thoughts\nKeep\n");
+    }
+
+    #[test]
+    fn test_replace_nested_section_stops_at_parent_sibling() {
+        // If the section was moved under another heading, it ends at the next heading of its level or above
+        let content =
+            "* Parent\n** readwise:highlights\n*** readwise:1\nOld\n** Sibling\nKeep\n* Other\n";
+        let result = replace_subtree(
+            content,
+            HIGHLIGHTS_HEADING,
+            "** readwise:highlights\n*** readwise:2\nNew",
+        );
+        assert_eq!(
+            result,
+            "* Parent\n** readwise:highlights\n*** readwise:2\nNew\n** Sibling\nKeep\n* Other\n"
+        );
+    }
+
+    const OLD_ANNOTATED: &str = "header
+* readwise:highlights
+** readwise:1
+First highlight
+- my comment on it
+*** note (2024-12-03)
+Old note
+** readwise:2
+Second highlight
+*** My thoughts
+Deep stuff
+** readwise:3
+Third highlight
+
+- extra comment by me
+* After
+";
+
+    #[test]
+    fn test_extract_annotations() {
+        let lines: Vec<&str> = OLD_ANNOTATED.lines().collect();
+        let rendered = [
+            "** readwise:1",
+            "First highlight",
+            "*** note (2024-12-04)",
+            "New note",
+        ];
+        // The note changed upstream, its old text isn't an annotation
+        assert_eq!(
+            extract_annotations(&lines[2..7], Some(&rendered), None),
+            Annotations {
+                body: vec!["- my comment on it".to_string()],
+                note: vec![],
+                children: vec![],
+            }
+        );
+        assert_eq!(
+            extract_annotations(&lines[7..11], None, None),
+            Annotations {
+                body: vec![],
+                note: vec![],
+                children: vec!["*** My thoughts".to_string(), "Deep stuff".to_string()],
+            }
+        );
+        // Without the rendered text, paragraphs are considered to be rendered, even several of them
+        assert!(extract_annotations(
+            &[
+                "** readwise:4",
+                "Text",
+                "",
+                "Second paragraph",
+                "*** note (2024-12-03)",
+                "Note"
+            ],
+            None,
+            None
+        )
+        .is_empty());
+        assert_eq!(
+            extract_annotations(
+                &[
+                    "** readwise:4",
+                    "Text",
+                    "- my comment",
+                    "  on two lines",
+                    "*** note (2024-12-03)",
+                    "Note",
+                    "- my reply"
+                ],
+                None,
+                None
+            ),
+            Annotations {
+                body: vec!["- my comment".to_string(), "  on two lines".to_string()],
+                note: vec!["- my reply".to_string()],
+                children: vec![],
+            }
+        );
+        // What the user wrote under the note heading is kept, not the old or new text of the note
+        assert_eq!(
+            extract_annotations(
+                &lines[2..7]
+                    .iter()
+                    .copied()
+                    .chain(["", "My reply to the note"])
+                    .collect::<Vec<_>>(),
+                Some(&rendered),
+                Some(&[
+                    "** readwise:1",
+                    "First highlight",
+                    "*** note (2024-12-03)",
+                    "Old note"
+                ]),
+            ),
+            Annotations {
+                body: vec!["- my comment on it".to_string()],
+                note: vec!["My reply to the note".to_string()],
+                children: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_is_list_item() {
+        assert!(is_list_item("- item"));
+        assert!(is_list_item("  + item"));
+        assert!(is_list_item("  * item"));
+        assert!(is_list_item("1. item"));
+        assert!(is_list_item("12) item"));
+        assert!(is_list_item("-"));
+        assert!(!is_list_item("* heading"));
+        assert!(!is_list_item("-dash"));
+        assert!(!is_list_item("Text"));
+    }
+
+    #[test]
+    fn test_highlight_blocks() {
+        let lines: Vec<&str> = OLD_ANNOTATED.lines().collect();
+        assert_eq!(
+            highlight_blocks(&lines),
+            vec![("1", 2, 7), ("2", 7, 11), ("3", 11, 15)]
+        );
+    }
+
+    #[test]
+    fn test_merge_annotations() {
+        let new_section = "* readwise:highlights
+** readwise:1
+First highlight
+*** note (2024-12-03)
+Old note
+** readwise:2
+Second highlight
+** readwise:4
+Brand new highlight";
+        let (merged, orphans) = merge_annotations(OLD_ANNOTATED, new_section, None);
+        assert_eq!(
+            merged,
+            "* readwise:highlights
+** readwise:1
+First highlight
+- my comment on it
+*** note (2024-12-03)
+Old note
+** readwise:2
+Second highlight
+*** My thoughts
+Deep stuff
+** readwise:4
+Brand new highlight"
+        );
+        assert_eq!(
+            orphans,
+            vec![
+                "** readwise:3",
+                "Third highlight",
+                "",
+                "- extra comment by me"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_after_highlight_edited_upstream() {
+        let old = "* readwise:highlights\n** readwise:1\nOld text\n- my note";
+        let previous_section = "* readwise:highlights\n** readwise:1\nOld text";
+        let new_section = "* readwise:highlights\n** readwise:1\nNew text";
+        assert_eq!(
+            merge_annotations(old, new_section, Some(previous_section)),
+            (
+                "* readwise:highlights\n** readwise:1\nNew text\n- my note".to_string(),
+                Vec::new()
+            )
+        );
+        // Nor is the old text of an annotated highlight deleted upstream kept as an annotation
+        let deleted = "* readwise:highlights\n** readwise:1\nOld text";
+        assert_eq!(
+            merge_annotations(deleted, "* readwise:highlights", Some(previous_section)),
+            ("* readwise:highlights".to_string(), Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_merge_keeps_replies_to_the_note() {
+        let old = "* readwise:highlights\n** readwise:1\nText\n*** note (2024-12-03)\nOld note\n- my reply\n*** My thoughts\nDeep stuff";
+        let previous_section =
+            "* readwise:highlights\n** readwise:1\nText\n*** note (2024-12-03)\nOld note";
+        let new_section =
+            "* readwise:highlights\n** readwise:1\nText\n*** note (2024-12-04)\nNew note";
+        assert_eq!(
+            merge_annotations(old, new_section, Some(previous_section)),
+            (
+                "* readwise:highlights\n** readwise:1\nText\n*** note (2024-12-04)\nNew note\n- my reply\n*** My thoughts\nDeep stuff".to_string(),
+                Vec::new()
+            )
+        );
+        // If the note was deleted upstream, the reply goes after the highlight text
+        assert_eq!(
+            merge_annotations(old, "* readwise:highlights\n** readwise:1\nText", Some(previous_section)),
+            (
+                "* readwise:highlights\n** readwise:1\nText\n- my reply\n*** My thoughts\nDeep stuff".to_string(),
+                Vec::new()
+            )
+        );
+    }
+
+    #[test]
+    fn test_merge_without_annotations_is_identity() {
+        let old = "* readwise:highlights\n** readwise:1\nText\n** readwise:2\nDeleted upstream\n";
+        let new_section = "* readwise:highlights\n** readwise:1\nText";
+        assert_eq!(
+            merge_annotations(old, new_section, None),
+            (new_section.to_string(), Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_append_orphans() {
+        let content = "header\n* readwise:highlights\n** readwise:1\nText\n* After\nKeep\n";
+        let orphans = vec![
+            "** readwise:3".to_string(),
+            "Old".to_string(),
+            "- comment".to_string(),
+        ];
+        let result = append_orphans(content, &orphans);
+        assert_eq!(
+            result,
+            format!(
+                "header\n* readwise:highlights\n** readwise:1\nText\n* {}\n{}\n** readwise:3\nOld\n- comment\n* After\nKeep\n",
+                ORPHANS_HEADING, ORPHANS_DESCRIPTION
+            )
+        );
+        // A second run appends to the existing section
+        let more = vec!["** readwise:5".to_string(), "- other".to_string()];
+        let result = append_orphans(&result, &more);
+        assert!(result.contains("- comment\n** readwise:5\n- other\n* After\nKeep\n"));
+        assert_eq!(result.matches(ORPHANS_HEADING).count(), 1);
+    }
+
+    #[test]
+    fn test_take_and_comment_out_highlight_blocks() {
+        let content = "* readwise:highlights\n** readwise:1\nOne\n** readwise:2\nTwo\n*** note (2024-12-03)\nNote\n** COMMENT readwise:0\nZero\n";
+        let (remaining, removed) = take_highlight_blocks(content, &["2".to_string()]);
+        assert_eq!(
+            remaining,
+            "* readwise:highlights\n** readwise:1\nOne\n** COMMENT readwise:0\nZero\n"
+        );
+        assert_eq!(
+            removed,
+            vec![vec![
+                "** readwise:2",
+                "Two",
+                "*** note (2024-12-03)",
+                "Note"
+            ]]
+        );
+        assert_eq!(
+            comment_out(&removed[0])[0],
+            "** COMMENT readwise:2".to_string()
+        );
+        assert_eq!(
+            commented_blocks(content),
+            vec!["** COMMENT readwise:0", "Zero"]
+        );
+        assert_eq!(highlight_ids(content), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn test_add_filetag() {
+        assert_eq!(
+            add_filetag("#+TITLE: Example\n\n- tags:\n", "readwise_deleted"),
+            "#+TITLE: Example\n#+filetags: :readwise_deleted:\n\n- tags:\n"
+        );
+        assert_eq!(
+            add_filetag("#+filetags: :book:\n#+TITLE: Example", "readwise_deleted"),
+            "#+filetags: :book:readwise_deleted:\n#+TITLE: Example"
+        );
+        // Tagging twice is a no-op
+        let once = add_filetag("#+TITLE: Example", "readwise_deleted");
+        assert_eq!(add_filetag(&once, "readwise_deleted"), once);
+    }
+}
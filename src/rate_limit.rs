@@ -0,0 +1,68 @@
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+
+// Token bucket shared by all the requests to the Reader API, so that concurrent fetches stay within its
+// per-minute budget instead of running into 429 responses.
+// Up to `burst` requests can be sent at once, then they are spaced so that no rolling minute ever has more
+// than `requests_per_minute` of them.
+pub struct RateLimiter {
+    capacity: f64,
+    // Tokens added per second
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: u32, burst: u32) -> Self {
+        let requests_per_minute = f64::from(requests_per_minute.max(2));
+        let capacity = f64::from(burst).clamp(1.0, requests_per_minute - 1.0);
+        Self {
+            capacity,
+            rate: (requests_per_minute - capacity) / 60.0,
+            bucket: Mutex::new(Bucket {
+                tokens: capacity,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub async fn acquire(&self) {
+        // Wait for a token. The lock is held while waiting, so that requests go out in the order they
+        // asked for a token.
+        let mut bucket = self.bucket.lock().await;
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.capacity);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            sleep(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate)).await;
+            bucket.tokens = 1.0;
+            bucket.updated = Instant::now();
+        }
+        bucket.tokens -= 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        // 2 requests at once, then one every 100ms
+        let limiter = RateLimiter::new(602, 2);
+        let start = Instant::now();
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        for _ in 0..3 {
+            limiter.acquire().await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(290));
+    }
+}
@@ -1,37 +1,123 @@
-use crate::util::clean_url;
-use crate::SETTINGS;
-
-use chrono::Utc;
+use crate::cache::{Cache, MergeResult};
+use crate::error::{Error, Result};
+use crate::http::build_client;
+use crate::rate_limit::RateLimiter;
+use crate::retry::{parse_expected_available, parse_retry_after, RetryPolicy};
+use crate::settings::Settings;
+use crate::token::{self, Token};
+use crate::util::{clean_url, write_atomically};
+
+use chrono::{DateTime, Utc};
+use futures::future::try_join_all;
+use log::{debug, info, warn};
+use reqwest::header::{HeaderValue, AUTHORIZATION, RETRY_AFTER};
 use reqwest::{Client, StatusCode};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use tokio::time::{sleep, Duration};
-
-#[derive(Debug, Clone)]
-pub struct Highlight {
+use std::path::Path;
+use tokio::time::{sleep, Instant};
+
+// An item of the Reader API list endpoint: a top-level document, a highlight or a note.
+// Apart from id and category, any field can be missing or null depending on the kind of item.
+// The whole schema is modeled, even though we don't use every field.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReaderItem {
     pub id: String,
-    pub parent_id: String,
-    pub content: String,
+    pub category: String,
+    pub url: Option<String>,
+    pub source_url: Option<String>,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub source: Option<String>,
+    pub location: Option<String>,
+    #[serde(default)]
+    pub tags: Option<HashMap<String, Tag>>,
+    pub site_name: Option<String>,
+    pub word_count: Option<u64>,
+    pub reading_time: Option<String>,
+    pub notes: Option<String>,
+    pub summary: Option<String>,
+    pub image_url: Option<String>,
+    pub content: Option<String>,
+    pub parent_id: Option<String>,
+    pub reading_progress: Option<f64>,
+    pub published_date: Option<PublishedDate>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub saved_at: Option<DateTime<Utc>>,
+    pub first_opened_at: Option<DateTime<Utc>>,
+    pub last_opened_at: Option<DateTime<Utc>>,
+    pub last_moved_at: Option<DateTime<Utc>>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tag {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub created: Option<i64>,
+}
+
+// published_date is either a Unix timestamp in milliseconds like 1064880000000, or a date like "2003-09-30"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum PublishedDate {
+    Timestamp(i64),
+    Date(String),
+}
+
+impl PublishedDate {
+    fn to_datetime(&self) -> Option<DateTime<Utc>> {
+        match self {
+            PublishedDate::Timestamp(timestamp) => DateTime::from_timestamp(timestamp / 1000, 0),
+            PublishedDate::Date(date) => date.parse::<DateTime<Utc>>().ok().or_else(|| {
+                chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                    .ok()
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+                    .map(|dt| dt.and_utc())
+            }),
+        }
+    }
 }
 
-fn get_string(
-    value: &serde_json::Value,
-    field: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
+impl ReaderItem {
+    fn parse(value: &serde_json::Value) -> Result<Self> {
+        Ok(Self::deserialize(value)?)
+    }
+}
+
+fn required<T: Clone>(field: &Option<T>, name: &str) -> Result<T> {
+    field
+        .clone()
+        .ok_or_else(|| Error::Parse(format!("Missing {}", name)))
+}
+
+fn get_string(value: &serde_json::Value, field: &str) -> Result<String> {
     Ok(value
         .get(field)
-        .ok_or(format!("Missing {}", field))?
+        .ok_or_else(|| Error::Parse(format!("Missing {}", field)))?
         .as_str()
-        .ok_or(format!("{} is not a string", field))?
+        .ok_or_else(|| Error::Parse(format!("{} is not a string", field)))?
         .to_string())
 }
 
+#[derive(Debug, Clone)]
+pub struct Highlight {
+    pub id: String,
+    pub parent_id: String,
+    pub content: String,
+}
+
 impl Highlight {
-    fn new(value: &serde_json::Value) -> Result<Self, Box<dyn std::error::Error>> {
+    fn new(value: &serde_json::Value) -> Result<Self> {
+        let item = ReaderItem::parse(value)?;
         Ok(Self {
-            id: get_string(value, "id")?,
-            parent_id: get_string(value, "parent_id")?,
-            content: get_string(value, "content")?,
+            parent_id: required(&item.parent_id, "parent_id")?,
+            content: item.content.unwrap_or_default(),
+            id: item.id,
         })
     }
 }
@@ -39,6 +125,8 @@ impl Highlight {
 #[derive(Debug, Clone)]
 pub struct Document {
     pub id: String,
+    // e.g. "article" or "epub"
+    pub category: String,
     // A document has a URL if the "source_url" field in the API results starts with http
     // (typically, otherwise the source_url starts with private://)
     pub has_url: bool,
@@ -48,97 +136,192 @@ pub struct Document {
     pub readwise_url: String,
     pub title: String,
     pub location: String,
-    pub author: String,
-    pub saved_at: chrono::DateTime<Utc>,
-    pub published_date: Option<chrono::DateTime<Utc>>,
+    pub author: Option<String>,
+    pub saved_at: DateTime<Utc>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub published_date: Option<DateTime<Utc>>,
+    pub tags: Vec<String>,
+    pub summary: Option<String>,
+    pub site_name: Option<String>,
+    pub word_count: Option<u64>,
+    pub reading_progress: Option<f64>,
+    pub image_url: Option<String>,
 }
 
 impl Document {
-    fn new(value: &serde_json::Value) -> Result<Self, Box<dyn std::error::Error>> {
-        let source_url = get_string(value, "source_url")?;
+    fn new(
+        value: &serde_json::Value,
+        keep_query_params: &HashMap<String, Vec<String>>,
+    ) -> Result<Self> {
+        let item = ReaderItem::parse(value)?;
+        let source_url = item.source_url.clone().unwrap_or_default();
         let has_url = source_url.starts_with("http");
-        let clean_url = clean_url(&source_url);
-        let id = get_string(value, "id")?;
-        // published_date is either Null, or a Unix timestamp like Number(1064880000000)
-        let published_date = match value.get("published_date").and_then(|v| v.as_i64()) {
-            Some(timestamp) => chrono::DateTime::from_timestamp(timestamp / 1000, 0),
-            None => None,
+        // Only URLs are cleaned, other source URLs (like private://) are kept as they are
+        let clean_url = if has_url {
+            clean_url(&source_url, keep_query_params)?
+        } else {
+            source_url
         };
-        let category = get_string(value, "category")?;
+        let published_date = item.published_date.as_ref().and_then(|d| d.to_datetime());
+        let title = required(&item.title, "title")?;
         // For a book with a published date, edit the title to be "Title (Year)"
-        let title = if category == "epub" && published_date.is_some() {
-            format!(
-                "{} ({})",
-                get_string(value, "title")?,
-                published_date.unwrap().format("%Y")
-            )
-        } else {
-            get_string(value, "title")?
+        let title = match published_date {
+            Some(date) if item.category == "epub" => format!("{} ({})", title, date.format("%Y")),
+            _ => title,
         };
+        let mut tags: Vec<String> = item
+            .tags
+            .unwrap_or_default()
+            .into_values()
+            .map(|tag| tag.name)
+            .collect();
+        tags.sort();
         Ok(Self {
-            id: id.clone(),
             has_url,
             roam_ref: match has_url {
                 true => clean_url.clone(),
-                false => format!("@readwise_{}", id),
+                false => format!("@readwise_{}", item.id),
             },
             source_url: clean_url,
-            readwise_url: get_string(value, "url")?,
+            readwise_url: required(&item.url, "url")?,
             title,
-            location: get_string(value, "location")?,
-            author: get_string(value, "author")?,
-            // saved_at is an ISO 8601 timestamp
-            saved_at: chrono::DateTime::parse_from_rfc3339(&get_string(value, "saved_at")?)
-                .unwrap()
-                .with_timezone(&Utc),
+            location: item.location.unwrap_or_default(),
+            author: item.author.filter(|a| !a.is_empty()),
+            saved_at: item
+                .saved_at
+                .or(item.created_at)
+                .ok_or_else(|| Error::Parse("Missing saved_at".to_string()))?,
+            updated_at: item.updated_at,
             published_date,
+            tags,
+            summary: item.summary.filter(|s| !s.is_empty()),
+            site_name: item.site_name.filter(|s| !s.is_empty()),
+            word_count: item.word_count,
+            reading_progress: item.reading_progress,
+            image_url: item.image_url.filter(|s| !s.is_empty()),
+            category: item.category,
+            id: item.id,
         })
     }
+
+    pub fn ref_candidates(&self) -> Vec<String> {
+        // All the refs under which an existing note for this document may have been saved, by order of preference
+        let mut candidates = vec![self.roam_ref.clone()];
+        for candidate in [self.source_url.clone(), format!("@readwise_{}", self.id)] {
+            if !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+        candidates
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Note {
     pub parent_id: String,
-    pub saved_at: String,
+    pub saved_at: DateTime<Utc>,
     pub content: String,
 }
 
 impl Note {
-    fn new(value: &serde_json::Value) -> Result<Self, Box<dyn std::error::Error>> {
+    fn new(value: &serde_json::Value) -> Result<Self> {
+        let item = ReaderItem::parse(value)?;
         Ok(Self {
-            parent_id: get_string(value, "parent_id")?,
-            saved_at: get_string(value, "saved_at")?,
-            content: get_string(value, "content")?,
+            parent_id: required(&item.parent_id, "parent_id")?,
+            saved_at: item
+                .saved_at
+                .or(item.created_at)
+                .ok_or_else(|| Error::Parse("Missing saved_at".to_string()))?,
+            content: item.content.unwrap_or_default(),
         })
     }
 }
 
-async fn fetch_readwise_data(
-    category: Option<&str>,
-    updated_after: Option<&str>,
-) -> Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
-    dotenv::from_path(SETTINGS.config_dir.join(".env")).ok();
-    let api_key = std::env::var("READWISE_API_KEY")?;
+// A client of the Reader API. All the requests of a run share its HTTP client, and its rate limiter
+// so that concurrent fetches stay within the API's rate limit.
+pub struct ReadwiseClient {
+    client: Client,
+    token: Token,
+    api_base_url: String,
+    rate_limiter: RateLimiter,
+    retry_policy: RetryPolicy,
+}
+
+impl ReadwiseClient {
+    pub fn new(settings: &Settings, client: Client, token: Token) -> Self {
+        Self {
+            client,
+            token,
+            api_base_url: settings.api_base_url.clone(),
+            rate_limiter: RateLimiter::new(
+                settings.rate_limit.requests_per_minute,
+                settings.rate_limit.burst,
+            ),
+            retry_policy: RetryPolicy::new(&settings.retry),
+        }
+    }
 
-    let client = Client::new();
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        // Load the API token from its configured source, and build the HTTP client from the [http] section
+        let token = token::load(&settings.token, &settings.config_dir)?;
+        let client = build_client(&settings.http)?;
+        Ok(Self::new(settings, client, token))
+    }
 
-    let mut all_results = Vec::new();
-    let mut next_cursor = None;
+    async fn fetch(&self, query: &[(&str, &str)]) -> Result<Vec<serde_json::Value>> {
+        // Return the items of all the pages of the list
+        let mut all_results = Vec::new();
+        let mut next_cursor = None;
 
-    loop {
-        let mut url = String::from("https://readwise.io/api/v3/list/");
-        let mut params = Vec::new();
+        loop {
+            let data = self.fetch_page(query, next_cursor.as_deref()).await?;
 
-        if let Some(cat) = category {
-            params.push(format!("category={}", cat));
-        }
+            let results = data
+                .get("results")
+                .and_then(|r| r.as_array())
+                .ok_or_else(|| Error::Api("No results found in response".to_string()))?;
+            all_results.extend(results.clone());
 
-        if let Some(cursor) = next_cursor {
-            params.push(format!("pageCursor={}", cursor));
+            next_cursor = data
+                .get("nextPageCursor")
+                .and_then(|c| c.as_str())
+                .map(String::from);
+
+            if next_cursor.is_none() {
+                break;
+            }
         }
 
-        if let Some(updated_after) = updated_after {
-            params.push(format!("updatedAfter={}", updated_after));
+        Ok(all_results)
+    }
+
+    pub async fn count(&self, query: &[(&str, &str)]) -> Result<u64> {
+        // Return the number of items of the list, which the first page gives
+        self.fetch_page(query, None)
+            .await?
+            .get("count")
+            .and_then(|c| c.as_u64())
+            .ok_or_else(|| Error::Api("No count found in response".to_string()))
+    }
+
+    async fn fetch_page(
+        &self,
+        query: &[(&str, &str)],
+        cursor: Option<&str>,
+    ) -> Result<serde_json::Value> {
+        // Marked as sensitive, so that it's redacted if the request is ever printed
+        let mut authorization = HeaderValue::from_str(&format!("Token {}", self.token.expose()))
+            .map_err(|_| Error::Config("The API token contains invalid characters".to_string()))?;
+        authorization.set_sensitive(true);
+
+        let mut url = format!("{}/list/", self.api_base_url.trim_end_matches('/'));
+        let mut params: Vec<String> = query
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+
+        if let Some(cursor) = cursor {
+            params.push(format!("pageCursor={}", cursor));
         }
 
         if !params.is_empty() {
@@ -146,123 +329,368 @@ async fn fetch_readwise_data(
             url.push_str(&params.join("&"));
         }
 
-        println!("Fetching {}...", url);
+        debug!("Fetching {}...", url);
 
-        let mut retry_count = 0;
-        let max_retries = 5;
-        let response = loop {
-            let response = client
+        let started = Instant::now();
+        let mut attempt = 0;
+        let body = loop {
+            self.rate_limiter.acquire().await;
+            let response = self
+                .client
                 .get(&url)
-                .header("Authorization", format!("Token {}", api_key))
+                .header(AUTHORIZATION, authorization.clone())
+                .timeout(self.retry_policy.request_timeout)
                 .send()
-                .await?;
-
-            let status = response.status();
-            match status {
-                StatusCode::OK => break response,
-                StatusCode::TOO_MANY_REQUESTS => {
-                    let response_text = response.text().await?;
-                    println!("{} - {}", status, response_text);
-                    if retry_count >= max_retries {
-                        return Err(format!(
-                            "Still getting rate limited despite {} retries",
-                            retry_count
-                        )
-                        .into());
+                .await;
+
+            // Why the attempt failed, and how long the server asked us to wait before the next one
+            let (failure, requested_wait) = match response {
+                Err(e) if e.is_builder() => return Err(e.into()),
+                Err(e) => (format!("Request failed: {}", e), None),
+                Ok(response) => {
+                    let status = response.status();
+                    let retry_after = response
+                        .headers()
+                        .get(RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| parse_retry_after(value, Utc::now()));
+                    let text = response.text().await;
+                    match (status, text) {
+                        (StatusCode::OK, Ok(text)) => break text,
+                        (_, Err(e)) => (format!("Failed to read response: {}", e), None),
+                        (StatusCode::TOO_MANY_REQUESTS, Ok(text)) => {
+                            let wait = retry_after.or_else(|| parse_expected_available(&text));
+                            (format!("{} - {}", status, text), wait)
+                        }
+                        (status, Ok(text)) if status.is_server_error() => {
+                            (format!("{} - {}", status, text), retry_after)
+                        }
+                        (status, Ok(text)) => {
+                            return Err(Error::Api(format!(
+                                "Unexpected status: {} - {}",
+                                status, text
+                            )));
+                        }
                     }
-                    // The response text looks like {"detail":"Request was throttled. Expected available in 50 seconds."}
-                    // Try to parse the wait time from response
-                    let wait_s = if let Some(seconds) = response_text
-                        .split("Expected available in ")
-                        .nth(1)
-                        .and_then(|s| s.split(' ').next())
-                        .and_then(|n| n.parse::<u64>().ok())
-                    {
-                        // Add 5 seconds buffer
-                        println!("Waiting {} seconds before retry...", seconds + 5);
-                        seconds + 5
-                    } else {
-                        // Default to 60s if parsing fails
-                        println!(
-                            "Failed to parse wait time from response. Waiting 60s before retry..."
-                        );
-                        60
-                    };
-
-                    sleep(Duration::from_secs(wait_s)).await;
-                    retry_count += 1;
                 }
-                _ => {
-                    return Err(format!(
-                        "Unexpected status: {} - {}",
-                        status,
-                        response.text().await?
-                    )
-                    .into());
+            };
+            warn!("{}", failure);
+            let wait = self
+                .retry_policy
+                .next_wait(attempt, requested_wait, started.elapsed())
+                .map_err(|reason| Error::Api(format!("{} ({})", failure, reason)))?;
+            warn!("Waiting {:.1} seconds before retry...", wait.as_secs_f64());
+            sleep(wait).await;
+            attempt += 1;
+        };
+
+        serde_json::from_str(&body).map_err(|e| Error::Api(format!("Invalid response: {}", e)))
+    }
+}
+
+pub fn list_query<'a>(
+    category: &'a str,
+    updated_after: Option<&'a str>,
+) -> Vec<(&'a str, &'a str)> {
+    let mut query = vec![("category", category)];
+    if let Some(updated_after) = updated_after {
+        query.push(("updatedAfter", updated_after));
+    }
+    query
+}
+
+// Fetches the lists of the Reader API, and merges them into the local cache
+pub struct Fetcher<'a> {
+    client: &'a ReadwiseClient,
+    settings: &'a Settings,
+    cache: Cache,
+}
+
+impl<'a> Fetcher<'a> {
+    pub fn new(client: &'a ReadwiseClient, settings: &'a Settings, dry_run: bool) -> Self {
+        // In a dry run, the fetched items are merged with the cached ones, but the cache isn't saved
+        let cache = if dry_run {
+            Cache::read_only(settings.cache_dir.clone())
+        } else {
+            Cache::new(settings.cache_dir.clone())
+        };
+        Self {
+            client,
+            settings,
+            cache,
+        }
+    }
+
+    pub async fn get_document_list(
+        &self,
+        updated_after: Option<&str>,
+        failures: &mut Vec<ParseFailure>,
+    ) -> Result<Vec<Document>> {
+        // Return all documents of type "epub" or "article", fetching all the categories concurrently
+        let categories = &self.settings.document_categories;
+        let results = try_join_all(
+            categories
+                .iter()
+                .map(|category| self.fetch_with_cache(category, updated_after)),
+        )
+        .await?;
+
+        let mut all_documents = Vec::new();
+        for (category, result) in categories.iter().zip(results) {
+            info!("Number of {}s: {}", category, result.changed.len());
+            all_documents.extend(parse_documents(
+                &result.changed,
+                category,
+                self.settings,
+                failures,
+            ));
+        }
+
+        Ok(all_documents)
+    }
+
+    pub async fn get_documents_by_id(
+        &self,
+        ids: &[String],
+        failures: &mut Vec<ParseFailure>,
+    ) -> Result<Vec<Document>> {
+        // Return the documents with the given ids, one request each, skipping those outside of document_categories
+        let results = try_join_all(
+            ids.iter()
+                .map(|id| async move { self.client.fetch(&[("id", id)]).await }),
+        )
+        .await?;
+        let mut documents = Vec::new();
+        for results in results {
+            for value in results {
+                let Ok(category) = get_string(&value, "category") else {
+                    continue;
+                };
+                if !self.settings.document_categories.contains(&category) {
+                    continue;
                 }
+                self.cache.merge(&category, vec![value.clone()], false)?;
+                documents.extend(parse_documents(
+                    &[value],
+                    &category,
+                    self.settings,
+                    failures,
+                ));
             }
-        };
+        }
+        Ok(documents)
+    }
 
-        let data: serde_json::Value = response.json().await?;
+    pub async fn get_document_ids(&self) -> Result<HashSet<String>> {
+        // Return the ids of all the documents that currently exist upstream, including those that fail to parse
+        let categories = &self.settings.document_categories;
+        let all_results =
+            try_join_all(categories.iter().map(|category| async move {
+                self.client.fetch(&list_query(category, None)).await
+            }))
+            .await?;
+        let mut ids = HashSet::new();
+        for (category, results) in categories.iter().zip(all_results) {
+            ids.extend(
+                results
+                    .iter()
+                    .filter_map(|value| value.get("id").and_then(|id| id.as_str()))
+                    .map(String::from),
+            );
+            // This is a full fetch, which also refreshes the cache
+            self.cache.merge(category, results, true)?;
+        }
+        Ok(ids)
+    }
 
-        let results = data
-            .get("results")
-            .and_then(|r| r.as_array())
-            .ok_or("No results found in response")?;
-        all_results.extend(results.clone());
+    pub fn settings(&self) -> &Settings {
+        self.settings
+    }
 
-        next_cursor = data
-            .get("nextPageCursor")
-            .and_then(|c| c.as_str())
-            .map(String::from);
+    fn needs_full_fetch(&self, category: &str) -> bool {
+        // The cache can't tell which items were deleted upstream, so it's periodically rebuilt from a full fetch
+        match self.cache.last_full_fetch(category) {
+            Some(last) => {
+                Utc::now() - last > chrono::Duration::days(self.settings.full_refresh_days)
+            }
+            None => true,
+        }
+    }
 
-        if next_cursor.is_none() {
-            break;
+    async fn fetch_with_cache(
+        &self,
+        category: &str,
+        updated_after: Option<&str>,
+    ) -> Result<MergeResult> {
+        // Fetch only the items updated since updated_after (or all of them if the cache needs a full refresh),
+        // and merge them into the cache. Concurrent fetches are joined within a single task, and a merge doesn't
+        // await anything, so merges never overlap.
+        let periodic_refresh = updated_after.is_some() && self.needs_full_fetch(category);
+        let full = updated_after.is_none() || periodic_refresh;
+        let updated_after = if full { None } else { updated_after };
+        let fetched = self
+            .client
+            .fetch(&list_query(category, updated_after))
+            .await?;
+        let mut result = self.cache.merge(category, fetched.clone(), full)?;
+        // Outside of a periodic refresh, everything we fetched is what changed since updated_after
+        if !periodic_refresh {
+            result.changed = fetched;
         }
+        Ok(result)
+    }
+
+    pub async fn get_note_list(
+        &self,
+        updated_after: Option<&str>,
+        failures: &mut Vec<ParseFailure>,
+    ) -> Result<(Vec<Note>, HashSet<String>)> {
+        // Return all notes, and the ids of the highlights whose note changed since updated_after
+        let result = self.fetch_with_cache("note", updated_after).await?;
+        info!(
+            "Number of notes: {} ({} changed, {} removed)",
+            result.items.len(),
+            result.changed.len(),
+            result.removed.len()
+        );
+        let changed_highlight_ids = result
+            .changed
+            .iter()
+            .chain(result.removed.iter())
+            .filter_map(|value| get_string(value, "parent_id").ok())
+            .collect();
+        Ok((parse_notes(&result.items, failures), changed_highlight_ids))
     }
 
-    Ok(all_results)
+    pub async fn get_highlight_list(
+        &self,
+        updated_after: Option<&str>,
+        failures: &mut Vec<ParseFailure>,
+    ) -> Result<(Vec<Highlight>, HashSet<String>)> {
+        // Return all highlights, and the ids of the documents whose highlights changed since updated_after
+        let result = self.fetch_with_cache("highlight", updated_after).await?;
+        info!(
+            "Number of highlights: {} ({} changed, {} removed)",
+            result.items.len(),
+            result.changed.len(),
+            result.removed.len()
+        );
+        let changed_parent_ids = result
+            .changed
+            .iter()
+            .chain(result.removed.iter())
+            .filter_map(|value| get_string(value, "parent_id").ok())
+            .collect();
+        Ok((
+            parse_highlights(&result.items, failures),
+            changed_parent_ids,
+        ))
+    }
 }
 
-pub async fn get_document_list(
-    updated_after: Option<&str>,
-) -> Result<Vec<Document>, Box<dyn std::error::Error>> {
-    // Return all documents of type "epub" or "article"
-    let mut all_documents = Vec::new();
+pub fn highlights_cached(settings: &Settings) -> bool {
+    // Whether we have a complete local copy of the highlights and notes to update incrementally
+    let cache = Cache::new(settings.cache_dir.clone());
+    ["highlight", "note"]
+        .iter()
+        .all(|category| cache.last_full_fetch(category).is_some())
+}
 
-    for category in &SETTINGS.document_categories {
-        let results = fetch_readwise_data(Some(category), updated_after).await?;
-        println!("Number of {}s: {}", category, results.len());
-        let documents: Vec<Document> = results
-            .into_iter()
-            .filter_map(|value| Document::new(&value).ok())
-            .collect();
-        all_documents.extend(documents);
+// An item of the API that couldn't be parsed, and was skipped
+#[derive(Debug, Clone, Serialize)]
+pub struct ParseFailure {
+    pub id: String,
+    pub category: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for ParseFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}: {}", self.category, self.id, self.reason)
     }
+}
 
-    Ok(all_documents)
+fn parse_items<T>(
+    values: &[serde_json::Value],
+    category: &str,
+    parse: impl Fn(&serde_json::Value) -> Result<T>,
+    failures: &mut Vec<ParseFailure>,
+) -> Vec<T> {
+    values
+        .iter()
+        .filter_map(|value| match parse(value) {
+            Ok(item) => Some(item),
+            Err(e) => {
+                failures.push(ParseFailure {
+                    id: get_string(value, "id").unwrap_or_else(|_| "<no id>".to_string()),
+                    category: get_string(value, "category")
+                        .unwrap_or_else(|_| category.to_string()),
+                    reason: e.to_string(),
+                });
+                None
+            }
+        })
+        .collect()
 }
 
-pub async fn get_note_list() -> Result<Vec<Note>, Box<dyn std::error::Error>> {
-    let json_results = fetch_readwise_data(Some("note"), None).await?;
-    println!("Number of notes: {}", json_results.len());
-    let notes = json_results
-        .into_iter()
-        .filter_map(|value| Note::new(&value).ok())
-        .collect();
-    Ok(notes)
+fn parse_documents(
+    values: &[serde_json::Value],
+    category: &str,
+    settings: &Settings,
+    failures: &mut Vec<ParseFailure>,
+) -> Vec<Document> {
+    let parse = |value: &serde_json::Value| Document::new(value, &settings.keep_query_params);
+    parse_items(values, category, parse, failures)
 }
 
-pub async fn get_highlight_list() -> Result<Vec<Highlight>, Box<dyn std::error::Error>> {
-    let json_results = fetch_readwise_data(Some("highlight"), None).await?;
-    println!("Number of highlights: {}", json_results.len());
-    let highlights: Vec<Highlight> = json_results
+fn parse_notes(values: &[serde_json::Value], failures: &mut Vec<ParseFailure>) -> Vec<Note> {
+    parse_items(values, "note", Note::new, failures)
+}
+
+fn parse_highlights(
+    values: &[serde_json::Value],
+    failures: &mut Vec<ParseFailure>,
+) -> Vec<Highlight> {
+    parse_items(values, "highlight", Highlight::new, failures)
         .into_iter()
-        .filter_map(|value| Highlight::new(&value).ok())
         // There's a surprising number of empty highlights
         .filter(|h| !h.content.is_empty())
-        .collect();
-    Ok(highlights)
+        .collect()
+}
+
+// The same lists, read from the local cache without any network call
+
+pub fn get_cached_document_list(
+    settings: &Settings,
+    failures: &mut Vec<ParseFailure>,
+) -> Result<Vec<Document>> {
+    let cache = Cache::new(settings.cache_dir.clone());
+    let mut all_documents = Vec::new();
+    for category in &settings.document_categories {
+        all_documents.extend(parse_documents(
+            &cache.items(category)?,
+            category,
+            settings,
+            failures,
+        ));
+    }
+    Ok(all_documents)
+}
+
+pub fn get_cached_note_list(
+    settings: &Settings,
+    failures: &mut Vec<ParseFailure>,
+) -> Result<Vec<Note>> {
+    let cache = Cache::new(settings.cache_dir.clone());
+    Ok(parse_notes(&cache.items("note")?, failures))
+}
+
+pub fn get_cached_highlight_list(
+    settings: &Settings,
+    failures: &mut Vec<ParseFailure>,
+) -> Result<Vec<Highlight>> {
+    let cache = Cache::new(settings.cache_dir.clone());
+    Ok(parse_highlights(&cache.items("highlight")?, failures))
 }
 
 pub fn map_parents_to_highlights(
@@ -295,25 +723,28 @@ pub fn note_list_to_map(note_list: Vec<Note>) -> HashMap<String, Note> {
         .collect()
 }
 
-pub fn get_updated_after() -> Result<Option<String>, Box<dyn std::error::Error>> {
+pub fn get_updated_after(path: &Path) -> Result<Option<String>> {
     // Return the last updated_after date from the updated_after_file_path as a string,
     // or return None if the file doesn't exist.
     // Returns an error if the file exists but contains an invalid date.
-    let path = &SETTINGS.updated_after_file_path;
     if !path.exists() {
         return Ok(None);
     }
 
     // Try to read the existing date from the file and validate it can parse as a date
-    let contents = fs::read_to_string(path)?;
+    let contents = fs::read_to_string(path).map_err(Error::io(path))?;
     let trimmed = contents.trim();
 
     match trimmed.parse::<chrono::DateTime<Utc>>() {
         Ok(_) => Ok(Some(trimmed.to_string())),
-        Err(e) => Err(format!("Invalid date format in {}: {}", path.display(), e).into()),
+        Err(e) => Err(Error::Parse(format!(
+            "Invalid date format in {}: {}",
+            path.display(),
+            e
+        ))),
     }
 }
 
-pub fn save_updated_after(date: &str) {
-    fs::write(&SETTINGS.updated_after_file_path, date).unwrap();
+pub fn save_updated_after(path: &Path, date: &str) -> Result<()> {
+    write_atomically(path, date)
 }
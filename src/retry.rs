@@ -0,0 +1,152 @@
+use crate::settings::RetrySettings;
+
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+// When to retry a request to the Reader API that failed with a 429, a 5xx or a transport error
+pub struct RetryPolicy {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_wait: Duration,
+    pub request_timeout: Duration,
+    total_timeout: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(settings: &RetrySettings) -> Self {
+        Self {
+            max_retries: settings.max_retries,
+            initial_backoff: Duration::from_secs_f64(settings.initial_backoff_secs.max(0.0)),
+            max_wait: Duration::from_secs(settings.max_wait_secs),
+            request_timeout: Duration::from_secs(settings.request_timeout_secs),
+            total_timeout: Duration::from_secs(settings.total_timeout_secs),
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        // Exponential backoff with jitter: a random wait between half and all of initial_backoff * 2^attempt,
+        // so that concurrent requests that failed together don't retry together
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_wait);
+        backoff.mul_f64(0.5 + fastrand::f64() / 2.0)
+    }
+
+    pub fn next_wait(
+        &self,
+        attempt: u32,
+        requested: Option<Duration>,
+        elapsed: Duration,
+    ) -> Result<Duration, String> {
+        // Return how long to wait before the next attempt, using the wait requested by the server if any,
+        // or why we should give up
+        if attempt >= self.max_retries {
+            return Err(format!("giving up after {} retries", attempt));
+        }
+        let wait = requested.unwrap_or_else(|| self.backoff(attempt));
+        if wait > self.max_wait {
+            return Err(format!(
+                "asked to wait {}s, more than max_wait_secs",
+                wait.as_secs()
+            ));
+        }
+        if elapsed + wait > self.total_timeout {
+            return Err("giving up, total_timeout_secs would be exceeded".to_string());
+        }
+        Ok(wait)
+    }
+}
+
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    // A Retry-After header is either a number of seconds, or an HTTP date
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+pub fn parse_expected_available(body: &str) -> Option<Duration> {
+    // Without a Retry-After header, the body of a 429 looks like
+    // {"detail":"Request was throttled. Expected available in 50 seconds."}
+    let seconds = body
+        .split("Expected available in ")
+        .nth(1)?
+        .split(' ')
+        .next()?
+        .parse::<u64>()
+        .ok()?;
+    // Add 5 seconds buffer
+    Some(Duration::from_secs(seconds + 5))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy::new(&RetrySettings {
+            max_retries: 3,
+            initial_backoff_secs: 1.0,
+            max_wait_secs: 10,
+            request_timeout_secs: 5,
+            total_timeout_secs: 30,
+        })
+    }
+
+    #[test]
+    fn test_next_wait() {
+        let policy = policy();
+        let wait = policy.next_wait(0, None, Duration::ZERO).unwrap();
+        assert!(wait >= Duration::from_millis(500) && wait <= Duration::from_secs(1));
+        let wait = policy.next_wait(2, None, Duration::ZERO).unwrap();
+        assert!(wait >= Duration::from_secs(2) && wait <= Duration::from_secs(4));
+        // The backoff is capped by max_wait
+        let policy = RetryPolicy {
+            max_retries: 10,
+            ..policy
+        };
+        assert!(policy.next_wait(8, None, Duration::ZERO).unwrap() <= Duration::from_secs(10));
+
+        // The wait asked by the server is used as is, within the limits
+        let requested = Some(Duration::from_secs(7));
+        assert_eq!(
+            policy.next_wait(0, requested, Duration::ZERO),
+            Ok(Duration::from_secs(7))
+        );
+        assert!(policy
+            .next_wait(0, Some(Duration::from_secs(60)), Duration::ZERO)
+            .is_err());
+        assert!(policy
+            .next_wait(0, requested, Duration::from_secs(25))
+            .is_err());
+        assert!(policy.next_wait(10, None, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = "2024-12-03T19:00:00Z".parse().unwrap();
+        assert_eq!(parse_retry_after("30", now), Some(Duration::from_secs(30)));
+        assert_eq!(
+            parse_retry_after("Tue, 03 Dec 2024 19:01:00 GMT", now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_retry_after("Tue, 03 Dec 2024 18:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(
+            parse_expected_available(
+                r#"{"detail":"Request was throttled. Expected available in 50 seconds."}"#
+            ),
+            Some(Duration::from_secs(55))
+        );
+    }
+}
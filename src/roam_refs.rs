@@ -0,0 +1,215 @@
+use crate::error::{Error, Result};
+
+use ignore::{WalkBuilder, WalkState};
+use log::warn;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+pub fn get_existing_refs(
+    org_roam_dir: &Path,
+    org_file_extensions: &[String],
+) -> Result<HashMap<String, String>> {
+    // Walk org_roam_dir in parallel (respecting .gitignore and .ignore files) to find all ROAM_REFS lines.
+    // Return a mapping from each roam_ref to the full filename it was found in.
+    if !org_roam_dir.is_dir() {
+        return Err(Error::Config(format!(
+            "org_roam_dir {} is not a directory",
+            org_roam_dir.display()
+        )));
+    }
+    let (tx, rx) = mpsc::channel::<(PathBuf, Vec<String>)>();
+    WalkBuilder::new(org_roam_dir)
+        .require_git(false)
+        .build_parallel()
+        .run(|| {
+            let tx = tx.clone();
+            Box::new(move |entry| {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        warn!("Skipping unreadable entry: {}", e);
+                        return WalkState::Continue;
+                    }
+                };
+                if entry.file_type().is_some_and(|t| t.is_file())
+                    && has_org_extension(entry.path(), org_file_extensions)
+                {
+                    let refs = read_roam_refs(entry.path());
+                    if !refs.is_empty() {
+                        tx.send((entry.into_path(), refs)).ok();
+                    }
+                }
+                WalkState::Continue
+            })
+        });
+    drop(tx);
+
+    // Sort by path so that the result doesn't depend on the order in which the threads finished
+    let mut files: Vec<(PathBuf, Vec<String>)> = rx.into_iter().collect();
+    files.sort();
+
+    let mut refs_map = HashMap::new();
+    for (path, refs) in files {
+        for roam_ref in refs {
+            refs_map
+                .entry(roam_ref)
+                .or_insert_with(|| path.to_string_lossy().into_owned());
+        }
+    }
+    Ok(refs_map)
+}
+
+fn has_org_extension(path: &Path, org_file_extensions: &[String]) -> bool {
+    let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    org_file_extensions
+        .iter()
+        .any(|ext| file_name.ends_with(&format!(".{}", ext.trim_start_matches('.'))))
+}
+
+fn read_roam_refs(path: &Path) -> Vec<String> {
+    // Files that can't be read as text (e.g. encrypted ones) simply have no refs
+    let Ok(content) = std::fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    content.lines().flat_map(parse_roam_refs_line).collect()
+}
+
+pub fn parse_roam_refs_line(line: &str) -> Vec<String> {
+    // Return the refs of a `:ROAM_REFS:` property line, or nothing if the line isn't one.
+    // Like org-roam, the value is split on whitespace, and double quotes group a ref containing spaces.
+    let line = line.trim_start();
+    let Some(key) = line.get(..":ROAM_REFS:".len()) else {
+        return Vec::new();
+    };
+    if !key.eq_ignore_ascii_case(":ROAM_REFS:") {
+        return Vec::new();
+    }
+    split_refs(&line[key.len()..])
+}
+
+fn split_refs(value: &str) -> Vec<String> {
+    let mut refs = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '\\' if in_quotes => current.extend(chars.next()),
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    refs.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        refs.push(current);
+    }
+    refs.into_iter().map(|r| strip_link_brackets(&r)).collect()
+}
+
+fn strip_link_brackets(roam_ref: &str) -> String {
+    // Refs can also be written as org links: [[https://example.com]] or [[https://example.com][description]]
+    match roam_ref
+        .strip_prefix("[[")
+        .and_then(|r| r.strip_suffix("]]"))
+    {
+        Some(inner) => inner.split("][").next().unwrap_or(inner).to_string(),
+        None => roam_ref.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_single_ref() {
+        assert_eq!(
+            parse_roam_refs_line(":ROAM_REFS: https://example.com/x"),
+            vec!["https://example.com/x"]
+        );
+        assert_eq!(
+            parse_roam_refs_line(":ROAM_REFS: @readwise_01je6xkjvh0grqe2q4mqw2abtn"),
+            vec!["@readwise_01je6xkjvh0grqe2q4mqw2abtn"]
+        );
+    }
+
+    #[test]
+    fn test_parse_multiple_and_quoted_refs() {
+        assert_eq!(
+            parse_roam_refs_line(
+                r#":ROAM_REFS: https://a.com/x @citekey "https://b.com/y" "a \"quoted\" ref""#
+            ),
+            vec![
+                "https://a.com/x",
+                "@citekey",
+                "https://b.com/y",
+                r#"a "quoted" ref"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_link_refs() {
+        assert_eq!(
+            parse_roam_refs_line(":roam_refs: [[https://a.com/x][A]] [[https://b.com]]"),
+            vec!["https://a.com/x", "https://b.com"]
+        );
+    }
+
+    #[test]
+    fn test_get_existing_refs() {
+        let dir = std::env::temp_dir().join(format!(
+            "org-readwise-rust-test-roam-refs-{}",
+            std::process::id()
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+        let files = [
+            (".gitignore", "ignored/\n"),
+            (".ignore", "draft.org\n"),
+            ("ignored/note.org", ":ROAM_REFS: https://ignored.com\n"),
+            ("draft.org", ":ROAM_REFS: https://draft.com\n"),
+            ("a.org", ":ROAM_REFS: https://shared.com https://a.com\n"),
+            ("z/z.org", ":ROAM_REFS: https://shared.com\n"),
+            ("secret.org.gpg", ":ROAM_REFS: https://secret.com\n"),
+            ("notes.txt", ":ROAM_REFS: https://txt.com\n"),
+        ];
+        for (name, content) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let refs = get_existing_refs(&dir, &["org".to_string()]).unwrap();
+        assert_eq!(
+            refs,
+            HashMap::from([
+                ("https://shared.com".to_string(), path("a.org")),
+                ("https://a.com".to_string(), path("a.org")),
+            ])
+        );
+        let refs = get_existing_refs(&dir, &["org".to_string(), ".org.gpg".to_string()]).unwrap();
+        assert_eq!(
+            refs.get("https://secret.com"),
+            Some(&path("secret.org.gpg"))
+        );
+        assert_eq!(refs.len(), 3);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_parse_non_refs_lines() {
+        assert!(parse_roam_refs_line(":ID: 9950a8f8").is_empty());
+        assert!(parse_roam_refs_line("- text mentioning :ROAM_REFS: in passing").is_empty());
+        assert!(parse_roam_refs_line(":ROAM_REFS:").is_empty());
+        // A filename-like prefix, as rg used to output, is not a property line
+        assert!(parse_roam_refs_line("a.org::ROAM_REFS: https://a.com").is_empty());
+    }
+}
@@ -1,44 +1,448 @@
-use config::{Config, File};
-use once_cell::sync::Lazy;
+use crate::error::{Error, Result};
+
+use config::{Config, File, FileFormat, FileSourceString, Map, Value, ValueKind};
 use serde::Deserialize;
-use std::{collections::HashMap, path::PathBuf};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Settings {
+    // Where the .env file is looked for, set when loading config.toml
+    #[serde(default)]
     pub config_dir: PathBuf,
     pub org_roam_dir: PathBuf,
     pub templates_dir: PathBuf,
     pub updated_after_file_path: PathBuf,
+    // JSON file mapping each Readwise document to the org file written for it
+    #[serde(default = "default_state_file_path")]
+    pub state_file_path: PathBuf,
+    // Local copy of the highlights and notes, so that they can be fetched incrementally
+    #[serde(default = "default_cache_dir")]
+    pub cache_dir: PathBuf,
+    // Number of days after which the cache is rebuilt from a full fetch, to find out about deleted items
+    #[serde(default = "default_full_refresh_days")]
+    pub full_refresh_days: i64,
     pub document_categories: Vec<String>,
+    // Base URL of the Reader API, which can be pointed to a local server for testing
+    #[serde(default = "default_api_base_url")]
+    pub api_base_url: String,
+    #[serde(default)]
     pub keep_query_params: HashMap<String, Vec<String>>,
+    // Extensions of the files scanned for ROAM_REFS in org_roam_dir
+    #[serde(default = "default_org_file_extensions")]
+    pub org_file_extensions: Vec<String>,
+    // Keep what the user wrote inside each highlight heading when regenerating the highlights section
+    #[serde(default)]
+    pub preserve_annotations: bool,
+    // What to do with highlights and documents that were deleted in Readwise
+    #[serde(default)]
+    pub deleted_highlights: DeletedHighlightsPolicy,
+    #[serde(default)]
+    pub deleted_documents: DeletedDocumentsPolicy,
+    // Where files of deleted documents are moved to, relative to org_roam_dir
+    #[serde(default = "default_deleted_documents_dir")]
+    pub deleted_documents_dir: PathBuf,
+    // JSONL file where the items that failed to parse during the last run are written, if set
+    pub rejects_file_path: Option<PathBuf>,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default)]
+    pub retry: RetrySettings,
+    #[serde(default)]
+    pub http: HttpSettings,
+    // Where the Readwise API token comes from
+    #[serde(default)]
+    pub token: TokenSource,
+    #[serde(default)]
+    pub backup: BackupSettings,
+    #[serde(default)]
+    pub git: GitSettings,
+}
+
+// What to do with the org-roam directory when it's a git repository
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct GitSettings {
+    // Commit the files created or edited by each sync or render
+    pub auto_commit: bool,
+}
+
+// Copies of the files modified by each run, which undo-last-run restores
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BackupSettings {
+    pub enabled: bool,
+    // One timestamped directory per run is created in it
+    pub dir: PathBuf,
+    // Number of runs whose backups are kept, the oldest ones are removed
+    pub keep_runs: usize,
+}
+
+impl Default for BackupSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: PathBuf::from("backups"),
+            keep_runs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "source", rename_all = "lowercase")]
+pub enum TokenSource {
+    // An environment variable, which can also be set in a .env file in the config directory
+    Env {
+        #[serde(default = "default_token_env_var")]
+        var: String,
+    },
+    // A file only readable by its owner
+    File {
+        path: PathBuf,
+    },
+    // The first line of the output of a command, e.g. ["pass", "show", "readwise"]
+    Command {
+        command: Vec<String>,
+    },
+}
+
+impl Default for TokenSource {
+    fn default() -> Self {
+        TokenSource::Env {
+            var: default_token_env_var(),
+        }
+    }
+}
+
+fn default_token_env_var() -> String {
+    "READWISE_API_KEY".to_string()
+}
+
+// How to reach the Reader API
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HttpSettings {
+    // e.g. "http://proxy.example.com:3128", used for both http and https
+    pub proxy: Option<String>,
+    // PEM files of root certificates to trust in addition to the system ones, e.g. a private CA
+    pub ca_certificates: Vec<PathBuf>,
+    pub connect_timeout_secs: u64,
+    // Longest time without receiving any data from the server
+    pub read_timeout_secs: u64,
+    pub user_agent: String,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            ca_certificates: Vec::new(),
+            connect_timeout_secs: 10,
+            read_timeout_secs: 30,
+            user_agent: format!("org-readwise-rust/{}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+// Budget of requests to the Reader API, shared by all the concurrent fetches
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    // Readwise documents a limit of 20 requests per minute for the list endpoint
+    pub requests_per_minute: u32,
+    // Number of requests that can be sent at once before they get spaced out
+    pub burst: u32,
+}
+
+// How requests that fail with a 429, a 5xx or a transport error are retried
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetrySettings {
+    pub max_retries: u32,
+    // First wait of the exponential backoff, used when the server doesn't say how long to wait
+    pub initial_backoff_secs: f64,
+    // Longest single wait; a request the server asks to wait longer for fails
+    pub max_wait_secs: u64,
+    // Timeout of each attempt
+    pub request_timeout_secs: u64,
+    // Timeout of all the attempts of a request, including the waits between them
+    pub total_timeout_secs: u64,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff_secs: 2.0,
+            max_wait_secs: 300,
+            request_timeout_secs: 60,
+            total_timeout_secs: 1800,
+        }
+    }
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: 20,
+            burst: 5,
+        }
+    }
 }
 
-pub static SETTINGS: Lazy<Settings> = Lazy::new(|| {
-    let home_dir = std::env::var("HOME").expect("HOME environment variable not set");
-    let config_dir = PathBuf::from(&home_dir).join(".config/org-readwise-rust");
-    let config = Config::builder()
-        .set_default("config_dir", config_dir.to_string_lossy().to_string())
-        .unwrap()
-        .add_source(File::with_name(
-            &config_dir.join("config.toml").to_string_lossy(),
-        ))
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletedHighlightsPolicy {
+    // Remove them from the file
+    #[default]
+    Drop,
+    // Keep them in the highlights section, with the COMMENT keyword on their heading
+    Comment,
+    // Move them to a `* readwise:deleted` section
+    Move,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletedDocumentsPolicy {
+    // Don't look for deleted documents (this requires fetching the full document list)
+    #[default]
+    Ignore,
+    // Add the readwise_deleted filetag to their file
+    Tag,
+    // Move their file to deleted_documents_dir
+    Move,
+}
+
+fn default_api_base_url() -> String {
+    "https://readwise.io/api/v3".to_string()
+}
+
+fn default_state_file_path() -> PathBuf {
+    PathBuf::from("sync_state.json")
+}
+
+fn default_cache_dir() -> PathBuf {
+    PathBuf::from("cache")
+}
+
+fn default_full_refresh_days() -> i64 {
+    7
+}
+
+fn default_deleted_documents_dir() -> PathBuf {
+    PathBuf::from("readwise_deleted")
+}
+
+fn default_org_file_extensions() -> Vec<String> {
+    vec!["org".to_string()]
+}
+
+fn home_dir() -> Result<PathBuf> {
+    std::env::var("HOME")
+        .map(PathBuf::from)
+        .map_err(|_| Error::Config("HOME environment variable not set".to_string()))
+}
+
+pub fn default_config_path() -> Result<PathBuf> {
+    Ok(home_dir()?.join(".config/org-readwise-rust/config.toml"))
+}
+
+fn read_config(
+    source: File<FileSourceString, FileFormat>,
+    origin: &str,
+) -> Result<Map<String, Value>> {
+    // Return the keys of a config.toml, including the [profiles.<name>] tables
+    Config::builder()
+        .add_source(source)
         .build()
-        .expect("Failed to load configuration from ~/.config/org-readwise-rust/config.toml");
+        .and_then(|config| config.cache.into_table())
+        .map_err(|e| Error::Config(format!("{}: {}", origin, e)))
+}
 
-    let mut settings = config.try_deserialize::<Settings>().unwrap();
+fn read_config_file(config_path: &Path) -> Result<Map<String, Value>> {
+    let contents = std::fs::read_to_string(config_path).map_err(Error::io(config_path))?;
+    read_config(
+        File::from_str(&contents, FileFormat::Toml),
+        &config_path.to_string_lossy(),
+    )
+}
 
-    // Expand ~ to home directory for all PathBuf fields
-    for path in [
-        &mut settings.org_roam_dir,
-        &mut settings.templates_dir,
-        &mut settings.updated_after_file_path,
-    ] {
-        if path.starts_with("~") {
-            *path = PathBuf::from(&home_dir).join(path.strip_prefix("~").unwrap());
+pub fn profile_names(config_path: &Path) -> Result<Vec<String>> {
+    // Return the names of the profiles defined in a config.toml, in alphabetical order
+    let mut names: Vec<String> = match read_config_file(config_path)?.remove("profiles") {
+        Some(profiles) => profiles
+            .into_table()
+            .map_err(|e| Error::Config(format!("profiles: {}", e)))?
+            .into_keys()
+            .collect(),
+        None => Vec::new(),
+    };
+    names.sort();
+    Ok(names)
+}
+
+fn merge_tables(base: &mut Map<String, Value>, overrides: Map<String, Value>) {
+    // Override the keys of base with those of overrides, merging the tables present in both
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value.kind) {
+            (
+                Some(Value {
+                    kind: ValueKind::Table(base_table),
+                    ..
+                }),
+                ValueKind::Table(table),
+            ) => merge_tables(base_table, table),
+            (_, kind) => {
+                base.insert(key, Value::new(None, kind));
+            }
         }
-        if path.is_relative() {
-            *path = config_dir.join(path.clone());
+    }
+}
+
+// The settings holding the files written by each run, as paths of keys in config.toml
+const STATE_PATH_KEYS: [&[&str]; 5] = [
+    &["updated_after_file_path"],
+    &["state_file_path"],
+    &["cache_dir"],
+    &["rejects_file_path"],
+    &["backup", "dir"],
+];
+
+fn get_string(table: &Map<String, Value>, keys: &[&str]) -> Option<String> {
+    let (last, parents) = keys.split_last()?;
+    let mut table = table.clone();
+    for key in parents {
+        table = table.remove(*key)?.into_table().ok()?;
+    }
+    table.remove(*last)?.into_string().ok()
+}
+
+fn check_state_paths_not_shared(
+    config: &Map<String, Value>,
+    overrides: &Map<String, Value>,
+    name: &str,
+) -> Result<()> {
+    // A relative state path is made relative to the directory of each profile, but an absolute one would
+    // be shared by all the profiles inheriting it, and each of them would then skip the changes synced by
+    // the others
+    for keys in STATE_PATH_KEYS {
+        let Some(path) = get_string(config, keys) else {
+            continue;
+        };
+        if get_string(overrides, keys).is_none()
+            && (path.starts_with('~') || Path::new(&path).is_absolute())
+        {
+            return Err(Error::Config(format!(
+                "profiles.{}: {} = {:?} would be shared with the other profiles, make it relative or set it in each profile",
+                name,
+                keys.join("."),
+                path
+            )));
         }
     }
-    settings
-});
+    Ok(())
+}
+
+fn parse(mut config: Map<String, Value>, profile: Option<&str>, origin: &str) -> Result<Settings> {
+    // With a profile, the keys of its [profiles.<name>] table override the top-level ones
+    let profiles = config.remove("profiles");
+    if let Some(name) = profile {
+        let overrides = profiles
+            .and_then(|profiles| profiles.into_table().ok())
+            .and_then(|mut profiles| profiles.remove(name))
+            .ok_or_else(|| Error::Config(format!("Unknown profile: {}", name)))?
+            .into_table()
+            .map_err(|e| Error::Config(format!("profiles.{}: {}", name, e)))?;
+        check_state_paths_not_shared(&config, &overrides, name)?;
+        merge_tables(&mut config, overrides);
+    }
+    Value::new(None, config).try_deserialize().map_err(|e| {
+        let name = profile
+            .map(|p| format!(" (profile {})", p))
+            .unwrap_or_default();
+        Error::Config(format!("{}{}: {}", origin, name, e))
+    })
+}
+
+impl Settings {
+    pub fn load(config_path: &Path, profile: Option<&str>) -> Result<Self> {
+        // Load the settings from a config.toml (usually ~/.config/org-readwise-rust/config.toml), with
+        // relative paths made relative to its directory. The state files of a profile are kept in its
+        // profiles/<name> directory instead, so that profiles don't share them.
+        let config_dir = std::path::absolute(config_path)
+            .map_err(Error::io(config_path))?
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let mut settings = parse(
+            read_config_file(config_path)?,
+            profile,
+            &config_path.to_string_lossy(),
+        )?;
+        let state_dir = match profile {
+            None => config_dir.clone(),
+            Some(name) => {
+                let state_dir = config_dir.join("profiles").join(name);
+                std::fs::create_dir_all(&state_dir).map_err(Error::io(&state_dir))?;
+                state_dir
+            }
+        };
+        settings.config_dir = config_dir.clone();
+        settings.resolve_paths(&config_dir, &state_dir);
+        Ok(settings)
+    }
+
+    pub fn from_toml(contents: &str, profile: Option<&str>) -> Result<Self> {
+        // Parse the settings from the contents of a config.toml, leaving relative paths as they are
+        parse(
+            read_config(File::from_str(contents, FileFormat::Toml), "config")?,
+            profile,
+            "config",
+        )
+    }
+
+    pub fn resolve_paths(&mut self, base_dir: &Path, state_dir: &Path) {
+        // Expand ~ to the home directory, and make relative paths relative to base_dir, or to
+        // state_dir for the files written by each run
+        let home_dir = home_dir().ok();
+        let state_paths = [
+            &mut self.updated_after_file_path,
+            &mut self.state_file_path,
+            &mut self.cache_dir,
+            &mut self.backup.dir,
+        ]
+        .into_iter()
+        .chain(self.rejects_file_path.as_mut());
+        let other_paths = [
+            &mut self.config_dir,
+            &mut self.org_roam_dir,
+            &mut self.templates_dir,
+        ]
+        .into_iter()
+        .chain(self.http.ca_certificates.iter_mut())
+        .chain(match &mut self.token {
+            TokenSource::File { path } => Some(path),
+            _ => None,
+        });
+        for (path, dir) in state_paths
+            .map(|path| (path, state_dir))
+            .chain(other_paths.map(|path| (path, base_dir)))
+        {
+            if let (Some(home_dir), Ok(relative)) = (&home_dir, path.strip_prefix("~")) {
+                *path = home_dir.join(relative);
+            }
+            if path.is_relative() {
+                *path = dir.join(path.clone());
+            }
+        }
+        // Except for deleted_documents_dir, which is relative to org_roam_dir
+        let deleted_documents_dir = &self.deleted_documents_dir;
+        if let (Some(home_dir), Ok(relative)) = (&home_dir, deleted_documents_dir.strip_prefix("~"))
+        {
+            self.deleted_documents_dir = home_dir.join(relative);
+        } else if deleted_documents_dir.is_relative() {
+            self.deleted_documents_dir = self.org_roam_dir.join(deleted_documents_dir);
+        }
+    }
+}
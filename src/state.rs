@@ -0,0 +1,289 @@
+use crate::error::{Error, Result};
+use crate::readwise_api::Document;
+
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// What we know about the org files written by previous runs, persisted as JSON in the state file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    // Readwise document id -> state of the org file for that document
+    pub documents: BTreeMap<String, DocumentState>,
+    // Missing from the state files written before it was recorded
+    #[serde(default)]
+    pub last_run: Option<LastRun>,
+}
+
+// What the last sync or render did, shown by the status command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LastRun {
+    pub command: String,
+    pub finished_at: DateTime<Utc>,
+    pub files_created: usize,
+    pub files_edited: usize,
+    pub documents_failed: usize,
+    pub parse_failures: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentState {
+    pub file: String,
+    // The category of the document, missing from the state files written before it was recorded
+    #[serde(default)]
+    pub category: Option<String>,
+    pub roam_ref: String,
+    // MD5 hash of the last rendered highlights section
+    pub content_hash: String,
+    pub highlight_ids: Vec<String>,
+    // The last rendered highlights section, to tell the text we wrote from the annotations added to it.
+    // Missing from the state files written before it was recorded.
+    #[serde(default)]
+    pub highlight_content: Option<String>,
+}
+
+impl SyncState {
+    pub fn load(path: &Path) -> Result<Self> {
+        // Return the saved state, or an empty one if the file doesn't exist yet
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(path).map_err(Error::io(path))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| Error::Parse(format!("Invalid sync state in {}: {}", path.display(), e)))
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn file_for(&self, document_id: &str) -> Option<&str> {
+        // Return the file previously written for this document, if it still exists
+        self.documents
+            .get(document_id)
+            .map(|d| d.file.as_str())
+            .filter(|file| Path::new(file).is_file())
+    }
+
+    pub fn record(
+        &mut self,
+        document: &Document,
+        file: &str,
+        highlight_content: &str,
+        highlight_ids: Vec<String>,
+    ) {
+        self.documents.insert(
+            document.id.clone(),
+            DocumentState {
+                file: file.to_string(),
+                category: Some(document.category.clone()),
+                roam_ref: document.roam_ref.clone(),
+                content_hash: format!("{:x}", md5::compute(highlight_content)),
+                highlight_ids,
+                highlight_content: Some(highlight_content.to_string()),
+            },
+        );
+    }
+}
+
+// Progress of a sync that didn't complete, so that the next run can skip the documents it already wrote.
+// Stored as JSONL next to the updated_after file: a header line with the updated_after date the sync
+// started from, then one line per document written, appended as soon as its file is written.
+pub struct Checkpoint {
+    path: PathBuf,
+    file: fs::File,
+    done: HashMap<String, CheckpointEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointHeader {
+    started_from: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointEntry {
+    id: String,
+    // What was written for the document, which must be the same for it to be skipped
+    fingerprint: String,
+    state: DocumentState,
+    // A hash of the file as it was written, to tell whether it was modified since
+    #[serde(default)]
+    file_hash: Option<String>,
+}
+
+fn file_hash(path: &Path) -> Option<String> {
+    fs::read(path)
+        .ok()
+        .map(|content| format!("{:x}", md5::compute(content)))
+}
+
+impl Checkpoint {
+    pub fn path_for(updated_after_file_path: &Path) -> PathBuf {
+        let mut file_name = updated_after_file_path
+            .file_name()
+            .unwrap_or_default()
+            .to_os_string();
+        file_name.push(".progress.jsonl");
+        updated_after_file_path.with_file_name(file_name)
+    }
+
+    pub fn written_documents(path: &Path) -> Option<usize> {
+        // Return the number of documents already written by an interrupted sync, if there is one
+        let contents = fs::read_to_string(path).ok()?;
+        Some(
+            contents
+                .lines()
+                .skip(1)
+                .filter(|line| !line.trim().is_empty())
+                .count(),
+        )
+    }
+
+    pub fn resume(path: &Path, started_from: Option<&str>) -> Result<Self> {
+        // Continue the checkpoint of an interrupted sync that started from the same updated_after date,
+        // or start a new one. A sync from another date may have missed changes, so its progress is dropped.
+        let mut done = HashMap::new();
+        if let Ok(contents) = fs::read_to_string(path) {
+            let mut lines = contents.lines();
+            let header: Option<CheckpointHeader> = lines
+                .next()
+                .and_then(|line| serde_json::from_str(line).ok());
+            if header.is_some_and(|h| h.started_from.as_deref() == started_from) {
+                // A line cut short by a crash is ignored, its document is simply written again
+                for entry in
+                    lines.filter_map(|line| serde_json::from_str::<CheckpointEntry>(line).ok())
+                {
+                    done.insert(entry.id.clone(), entry);
+                }
+            }
+        }
+        let file = if done.is_empty() {
+            let mut file = fs::File::create(path).map_err(Error::io(path))?;
+            let header = CheckpointHeader {
+                started_from: started_from.map(String::from),
+            };
+            writeln!(file, "{}", serde_json::to_string(&header)?).map_err(Error::io(path))?;
+            file
+        } else {
+            info!(
+                "Resuming an interrupted sync, {} documents were already written",
+                done.len()
+            );
+            fs::OpenOptions::new()
+                .append(true)
+                .open(path)
+                .map_err(Error::io(path))?
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            done,
+        })
+    }
+
+    pub fn unchanged_files(&self) -> impl Iterator<Item = &Path> {
+        // The files written by the documents done so far that weren't modified since
+        self.done
+            .values()
+            .map(|entry| (Path::new(&entry.state.file), &entry.file_hash))
+            .filter(|(path, hash)| hash.is_some() && file_hash(path) == **hash)
+            .map(|(path, _)| path)
+    }
+
+    pub fn completed(&self, document_id: &str, fingerprint: &str) -> Option<&DocumentState> {
+        // Return the state of the document if it was already written with the same content
+        self.done
+            .get(document_id)
+            .filter(|entry| entry.fingerprint == fingerprint)
+            .map(|entry| &entry.state)
+    }
+
+    pub fn record(
+        &mut self,
+        document_id: &str,
+        fingerprint: &str,
+        state: &DocumentState,
+    ) -> Result<()> {
+        let entry = CheckpointEntry {
+            id: document_id.to_string(),
+            fingerprint: fingerprint.to_string(),
+            state: state.clone(),
+            file_hash: file_hash(Path::new(&state.file)),
+        };
+        writeln!(self.file, "{}", serde_json::to_string(&entry)?)
+            .and_then(|_| self.file.sync_data())
+            .map_err(Error::io(&self.path))?;
+        self.done.insert(entry.id.clone(), entry);
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        // Once the updated_after date is saved, the progress of this sync is no longer needed
+        fs::remove_file(&self.path).map_err(Error::io(&self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document_state(file: &str) -> DocumentState {
+        DocumentState {
+            file: file.to_string(),
+            category: None,
+            roam_ref: "https://example.com".to_string(),
+            content_hash: String::new(),
+            highlight_ids: Vec::new(),
+            highlight_content: None,
+        }
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = std::env::temp_dir().join(format!(
+            "org-readwise-rust-test-checkpoint-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = Checkpoint::path_for(&dir.join("updated_after.txt"));
+        assert_eq!(path, dir.join("updated_after.txt.progress.jsonl"));
+
+        let date = Some("2024-12-01T00:00:00.000Z");
+        let mut checkpoint = Checkpoint::resume(&path, date).unwrap();
+        checkpoint
+            .record("a", "hash", &document_state("a.org"))
+            .unwrap();
+        drop(checkpoint);
+
+        // Resuming from the same date skips documents written with the same fingerprint
+        let mut checkpoint = Checkpoint::resume(&path, date).unwrap();
+        assert_eq!(checkpoint.completed("a", "hash").unwrap().file, "a.org");
+        assert!(checkpoint.completed("a", "other").is_none());
+        assert!(checkpoint.completed("b", "hash").is_none());
+
+        // Only the files that still have what was written to them are unchanged
+        let (b, c) = (dir.join("b.org"), dir.join("c.org"));
+        for (id, file) in [("b", &b), ("c", &c)] {
+            fs::write(file, "Written").unwrap();
+            let state = document_state(&file.to_string_lossy());
+            checkpoint.record(id, "hash", &state).unwrap();
+        }
+        fs::write(&c, "Modified since").unwrap();
+        assert_eq!(
+            checkpoint.unchanged_files().collect::<Vec<_>>(),
+            vec![b.as_path()]
+        );
+        drop(checkpoint);
+
+        // Another date may have missed changes, so the progress is dropped
+        let checkpoint = Checkpoint::resume(&path, None).unwrap();
+        assert!(checkpoint.completed("a", "hash").is_none());
+        checkpoint.finish().unwrap();
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,1121 @@
+use crate::backup::{self, Backup, UndoSummary};
+use crate::cache::Cache;
+use crate::error::{Error, Result};
+use crate::git::{self, AutoCommit};
+use crate::org;
+use crate::readwise_api::*;
+use crate::roam_refs::get_existing_refs;
+use crate::settings::{DeletedDocumentsPolicy, DeletedHighlightsPolicy, Settings};
+use crate::state::{Checkpoint, DocumentState, LastRun, SyncState};
+use crate::util::{emacs_lock, write_atomically};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::future::try_join_all;
+use log::{debug, info, warn};
+use once_cell::sync::OnceCell;
+use similar::TextDiff;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tera::{Context, Tera};
+
+// A document whose file couldn't be written, or tagged or moved after its deletion upstream
+pub struct DocumentFailure {
+    pub id: String,
+    pub title: String,
+    pub error: Error,
+}
+
+// What a run did
+#[derive(Default)]
+pub struct RunSummary {
+    pub files_created: usize,
+    pub files_edited: usize,
+    // Files already written by an interrupted sync
+    pub files_skipped: usize,
+    pub failed: Vec<DocumentFailure>,
+    // Documents whose file was being edited in Emacs, left for the next run
+    pub deferred: Vec<DocumentFailure>,
+    pub parse_failures: Vec<ParseFailure>,
+    // Nothing was written, the counts are those of the files that would have been
+    pub dry_run: bool,
+    // In a dry run, what would have changed: a unified diff of each file created or edited (from
+    // /dev/null for a new file), and a line for each file moved
+    pub changes: Vec<String>,
+    // In a dry run, the updated_after date that would have been saved
+    pub next_updated_after: Option<String>,
+}
+
+// How a sync or a render runs
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RunOptions {
+    // Fail if any item fails to parse, before any file is written
+    pub strict: bool,
+    // Write nothing (org files, state, local copy, updated_after date), and return what would change in
+    // the summary instead
+    pub dry_run: bool,
+}
+
+impl RunSummary {
+    fn add_failure(&mut self, failure: DocumentFailure) {
+        // A file being edited isn't a failure, the document is only deferred to the next run
+        if matches!(failure.error, Error::OpenInEmacs { .. }) {
+            self.deferred.push(failure);
+        } else {
+            self.failed.push(failure);
+        }
+    }
+
+    fn last_run(&self, command: &str) -> LastRun {
+        LastRun {
+            command: command.to_string(),
+            finished_at: Utc::now(),
+            files_created: self.files_created,
+            files_edited: self.files_edited,
+            documents_failed: self.failed.len(),
+            parse_failures: self.parse_failures.len(),
+        }
+    }
+}
+
+// Where the next sync starts from, and what's waiting for it
+pub struct Status {
+    pub updated_after: Option<String>,
+    pub last_run: Option<LastRun>,
+    // Number of documents already written by an interrupted sync, if there is one
+    pub interrupted_sync: Option<usize>,
+    pub synced_documents: usize,
+    // Number of items of each category updated upstream since updated_after, unless the Reader API
+    // couldn't be reached
+    pub pending: Result<Vec<(String, u64)>>,
+}
+
+// A document of the local copy, and the org file written for it
+pub struct ListedDocument {
+    pub id: String,
+    pub title: String,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub file: Option<String>,
+}
+
+// The outcome of one of the checks of the doctor command
+pub struct Check {
+    pub name: &'static str,
+    pub result: Result<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<String>) -> Self {
+        Self { name, result }
+    }
+}
+
+// Where and how the org files of the documents are written
+struct Writer<'a> {
+    settings: &'a Settings,
+    tera: Tera,
+    existing_refs: HashMap<String, String>,
+    sync_state: SyncState,
+    // Only set for a sync, to skip the documents already written by an interrupted one
+    checkpoint: Option<Checkpoint>,
+    files: Files,
+}
+
+impl<'a> Writer<'a> {
+    fn new(settings: &'a Settings, dry_run: bool) -> Result<Self> {
+        Ok(Self {
+            settings,
+            tera: Tera::new(&settings.templates_dir.to_string_lossy())?,
+            existing_refs: get_existing_refs(
+                &settings.org_roam_dir,
+                &settings.org_file_extensions,
+            )?,
+            sync_state: SyncState::load(&settings.state_file_path)?,
+            checkpoint: None,
+            files: Files {
+                dry_run,
+                changes: Vec::new(),
+                backup: Backup::start(&settings.backup).filter(|_| !dry_run),
+                git: if settings.git.auto_commit && !dry_run {
+                    Some(AutoCommit::start(&settings.org_roam_dir)?)
+                } else {
+                    None
+                },
+            },
+        })
+    }
+
+    fn commit(&self, command: &str, summary: &RunSummary) -> Result<()> {
+        // Commit the files written by this run, if auto_commit is set
+        let Some(git) = &self.files.git else {
+            return Ok(());
+        };
+        let subject = format!(
+            "Readwise {}: {} files created, {} edited",
+            command, summary.files_created, summary.files_edited
+        );
+        if let Some(id) = git.commit(&subject)? {
+            info!("Committed the changes as {}", id);
+        }
+        Ok(())
+    }
+
+    fn save_state(&mut self) -> Result<()> {
+        let state = self.sync_state.to_json()?;
+        self.files
+            .write(&self.settings.state_file_path, None, &state)
+    }
+}
+
+// Writes the files of a run atomically, after backing up their previous version so that the run can be
+// undone. In a dry run, records what would change instead.
+struct Files {
+    dry_run: bool,
+    // What a dry run would change
+    changes: Vec<String>,
+    backup: Option<Backup>,
+    git: Option<AutoCommit>,
+}
+
+impl Files {
+    fn write(&mut self, path: &Path, previous_content: Option<&str>, content: &str) -> Result<()> {
+        // In a dry run, record the change as a unified diff (from /dev/null for a new file)
+        if !self.dry_run {
+            if let Some(git) = &self.git {
+                git.check(path)?;
+            }
+            if let Some(backup) = &mut self.backup {
+                backup.save(path)?;
+            }
+            write_atomically(path, content)?;
+            if let Some(backup) = &mut self.backup {
+                backup.written(path, content)?;
+            }
+            if let Some(git) = &mut self.git {
+                git.add(path);
+            }
+            return Ok(());
+        }
+        if previous_content == Some(content) {
+            info!("No change to {}", path.display());
+            return Ok(());
+        }
+        let name = path.to_string_lossy();
+        let diff = TextDiff::from_lines(previous_content.unwrap_or_default(), content);
+        let old_name = if previous_content.is_some() {
+            &name
+        } else {
+            "/dev/null"
+        };
+        self.changes
+            .push(diff.unified_diff().header(old_name, &name).to_string());
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        if let Some(git) = &self.git {
+            git.check(from)?;
+        }
+        std::fs::rename(from, to).map_err(Error::io(from))?;
+        if let Some(backup) = &mut self.backup {
+            backup.moved(from, to)?;
+        }
+        if let Some(git) = &mut self.git {
+            git.add(from);
+            git.add(to);
+        }
+        Ok(())
+    }
+
+    fn describe(&mut self, change: impl FnOnce() -> String) {
+        // Say what was done to a file, in the commit message
+        if let Some(git) = &mut self.git {
+            git.describe(change());
+        }
+    }
+}
+
+// Syncs a Readwise library to an org-roam directory, as set in its settings
+pub struct Syncer {
+    settings: Settings,
+    // Built on the first sync, so that rendering doesn't need an API token
+    client: OnceCell<ReadwiseClient>,
+}
+
+impl Syncer {
+    pub fn new(settings: Settings) -> Self {
+        Self {
+            settings,
+            client: OnceCell::new(),
+        }
+    }
+
+    pub fn with_client(self, client: ReadwiseClient) -> Self {
+        // Use this client for the Reader API, instead of one built from the settings
+        Self {
+            client: OnceCell::with_value(client),
+            ..self
+        }
+    }
+
+    pub fn with_root(mut self, root: &Path) -> Self {
+        // Make the relative paths of the settings relative to root, instead of the current directory
+        self.settings.resolve_paths(root, root);
+        self
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    fn client(&self) -> Result<&ReadwiseClient> {
+        self.client
+            .get_or_try_init(|| ReadwiseClient::from_settings(&self.settings))
+    }
+
+    pub async fn sync(&self, options: RunOptions) -> Result<RunSummary> {
+        let settings = &self.settings;
+        // Fail early if there's no API token
+        let fetcher = Fetcher::new(self.client()?, settings, options.dry_run);
+        let mut writer = Writer::new(settings, options.dry_run)?;
+        // Without a complete local copy of the highlights, we can't tell which documents they changed
+        let last_updated_after = get_updated_after(&settings.updated_after_file_path)?
+            .filter(|_| highlights_cached(settings));
+        let next_updated_after = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let mut summary = RunSummary {
+            dry_run: options.dry_run,
+            ..Default::default()
+        };
+        // The documents, highlights and notes are fetched concurrently
+        let (mut document_failures, mut highlight_failures, mut note_failures) =
+            (Vec::new(), Vec::new(), Vec::new());
+        let (mut documents, (highlights, mut changed_parent_ids), (notes, changed_note_parent_ids)) =
+            tokio::try_join!(
+                fetcher.get_document_list(last_updated_after.as_deref(), &mut document_failures),
+                fetcher.get_highlight_list(last_updated_after.as_deref(), &mut highlight_failures),
+                fetcher.get_note_list(last_updated_after.as_deref(), &mut note_failures),
+            )?;
+        let parse_failures = &mut summary.parse_failures;
+        parse_failures.extend(document_failures);
+        parse_failures.extend(highlight_failures);
+        parse_failures.extend(note_failures);
+
+        // Editing a highlight or a note should mark its document as updated, but we don't rely on it:
+        // documents we synced before whose highlights or notes changed are fetched as well
+        changed_parent_ids.extend(
+            highlights
+                .iter()
+                .filter(|h| changed_note_parent_ids.contains(&h.id))
+                .map(|h| h.parent_id.clone()),
+        );
+        let missing_parent_ids: Vec<String> = changed_parent_ids
+            .into_iter()
+            .filter(|id| writer.sync_state.documents.contains_key(id))
+            .filter(|id| !documents.iter().any(|d| &d.id == id))
+            .collect();
+        if !missing_parent_ids.is_empty() {
+            documents.extend(
+                fetcher
+                    .get_documents_by_id(&missing_parent_ids, parse_failures)
+                    .await?,
+            );
+        }
+        // In strict mode, stop before writing anything and without saving the updated_after date, so
+        // that the next run fetches the same items again
+        check_parse_failures(settings, &summary.parse_failures, options)?;
+        if !options.dry_run {
+            write_rejects(settings, &summary.parse_failures)?;
+        }
+        // Only once the run can't fail anymore, since the files it changes would be left out of the state
+        handle_deleted_documents(&fetcher, &mut writer, &mut summary).await?;
+
+        if documents.is_empty() {
+            info!("No documents found to process. Exiting.");
+        } else if options.dry_run {
+            write_documents(&mut writer, &documents, highlights, notes, &mut summary);
+        } else {
+            // Each document written is checkpointed, so that if this sync doesn't complete, the next one
+            // (which starts from the same updated_after date) skips the documents that haven't changed since
+            let checkpoint_path = Checkpoint::path_for(&settings.updated_after_file_path);
+            writer.checkpoint = Some(Checkpoint::resume(
+                &checkpoint_path,
+                last_updated_after.as_deref(),
+            )?);
+            // The files written by an interrupted sync weren't committed, this sync commits them unless
+            // they were modified since
+            if let (Some(git), Some(checkpoint)) = (&mut writer.files.git, &writer.checkpoint) {
+                for file in checkpoint.unchanged_files() {
+                    git.adopt(file);
+                }
+            }
+            write_documents(&mut writer, &documents, highlights, notes, &mut summary);
+        }
+        if options.dry_run {
+            summary.changes = writer.files.changes;
+            summary.next_updated_after = Some(next_updated_after);
+            return Ok(summary);
+        }
+        writer.sync_state.last_run = Some(summary.last_run("sync"));
+        writer.save_state()?;
+        // Only save this if every document was written. Otherwise (or if the program crashes in the middle),
+        // the next run will still use the old updated_after date and no update from readwise will be lost.
+        if summary.failed.is_empty() && summary.deferred.is_empty() {
+            info!("Saving next updated_after date: {}", next_updated_after);
+            writer
+                .files
+                .write(&settings.updated_after_file_path, None, &next_updated_after)?;
+            if let Some(checkpoint) = writer.checkpoint.take() {
+                checkpoint.finish()?;
+            }
+        } else {
+            warn!("Some documents failed or were deferred, keeping the previous updated_after date so that they're retried");
+        }
+        writer.commit("sync", &summary)?;
+        Ok(summary)
+    }
+
+    pub fn render(&self, document_ids: &[String], options: RunOptions) -> Result<RunSummary> {
+        // Rebuild the org files of all the cached documents (or only those with the given ids) from the
+        // local cache, with the current templates, without any network call.
+        // The updated_after date is left untouched.
+        let settings = &self.settings;
+        let mut writer = Writer::new(settings, options.dry_run)?;
+        let mut summary = RunSummary {
+            dry_run: options.dry_run,
+            ..Default::default()
+        };
+        let parse_failures = &mut summary.parse_failures;
+        let documents: Vec<Document> = get_cached_document_list(settings, parse_failures)?
+            .into_iter()
+            .filter(|d| document_ids.is_empty() || document_ids.contains(&d.id))
+            .collect();
+        let highlights = get_cached_highlight_list(settings, parse_failures)?;
+        let notes = get_cached_note_list(settings, parse_failures)?;
+        check_parse_failures(settings, &summary.parse_failures, options)?;
+        if !options.dry_run {
+            write_rejects(settings, &summary.parse_failures)?;
+        }
+        if documents.is_empty() {
+            info!("No cached documents found to render. Exiting.");
+            return Ok(summary);
+        }
+        write_documents(&mut writer, &documents, highlights, notes, &mut summary);
+        if options.dry_run {
+            summary.changes = writer.files.changes;
+            return Ok(summary);
+        }
+        writer.sync_state.last_run = Some(summary.last_run("render"));
+        writer.save_state()?;
+        writer.commit("render", &summary)?;
+        Ok(summary)
+    }
+
+    pub async fn status(&self) -> Result<Status> {
+        // Return where the next sync starts from, what the last run did, and how many items changed
+        // upstream since (which takes one request per category)
+        let settings = &self.settings;
+        let updated_after = get_updated_after(&settings.updated_after_file_path)?;
+        let sync_state = SyncState::load(&settings.state_file_path)?;
+        let categories: Vec<&str> = settings
+            .document_categories
+            .iter()
+            .map(String::as_str)
+            .chain(["highlight", "note"])
+            .collect();
+        let pending = match self.client() {
+            Ok(client) => {
+                try_join_all(categories.iter().map(|category| async {
+                    let query = list_query(category, updated_after.as_deref());
+                    Ok((category.to_string(), client.count(&query).await?))
+                }))
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        Ok(Status {
+            interrupted_sync: Checkpoint::written_documents(&Checkpoint::path_for(
+                &settings.updated_after_file_path,
+            )),
+            synced_documents: sync_state.documents.len(),
+            last_run: sync_state.last_run,
+            updated_after,
+            pending,
+        })
+    }
+
+    pub fn reset_cursor(&self, to: Option<&str>) -> Result<()> {
+        // Make the next sync fetch everything updated after the given date (e.g. "2024-12-01" or
+        // "2024-12-01T10:00:00Z"), or everything if there's no date
+        let path = &self.settings.updated_after_file_path;
+        match to {
+            Some(date) => {
+                let date = parse_cursor_date(date)?;
+                save_updated_after(path, &date.to_rfc3339_opts(SecondsFormat::Millis, true))?;
+            }
+            None if path.exists() => std::fs::remove_file(path).map_err(Error::io(path))?,
+            None => {}
+        }
+        // The progress of an interrupted sync only applies to the date it started from
+        let checkpoint_path = Checkpoint::path_for(path);
+        if checkpoint_path.exists() {
+            std::fs::remove_file(&checkpoint_path).map_err(Error::io(&checkpoint_path))?;
+        }
+        Ok(())
+    }
+
+    pub fn undo_last_run(&self) -> Result<UndoSummary> {
+        // Put back the files modified by the last sync or render (including the sync state and the
+        // updated_after date) from their backups
+        let summary = backup::undo_last_run(&self.settings.backup)?;
+        // The progress of an interrupted sync was undone with it
+        let checkpoint_path = Checkpoint::path_for(&self.settings.updated_after_file_path);
+        if checkpoint_path.exists() {
+            std::fs::remove_file(&checkpoint_path).map_err(Error::io(&checkpoint_path))?;
+        }
+        Ok(summary)
+    }
+
+    pub fn list(&self) -> Result<Vec<ListedDocument>> {
+        // Return the documents of the local copy, with the file written for each of them if any
+        let settings = &self.settings;
+        let sync_state = SyncState::load(&settings.state_file_path)?;
+        let mut parse_failures = Vec::new();
+        let mut documents: Vec<ListedDocument> =
+            get_cached_document_list(settings, &mut parse_failures)?
+                .into_iter()
+                .map(|document| ListedDocument {
+                    file: sync_state.file_for(&document.id).map(String::from),
+                    id: document.id,
+                    title: document.title,
+                    updated_at: document.updated_at,
+                })
+                .collect();
+        documents.sort_by_key(|d| std::cmp::Reverse(d.updated_at));
+        Ok(documents)
+    }
+
+    pub async fn doctor(&self) -> Vec<Check> {
+        // Check each part of the setup, without writing anything
+        let settings = &self.settings;
+        // Loading the token may run a command that prompts for a password, so it's only done once
+        let (client, token) = match self.client() {
+            Ok(client) => (Some(client), Ok("found".to_string())),
+            Err(e) => (None, Err(e)),
+        };
+        let mut checks = vec![
+            Check::new(
+                "org_roam_dir",
+                get_existing_refs(&settings.org_roam_dir, &settings.org_file_extensions)
+                    .map(|refs| format!("{} refs found", refs.len())),
+            ),
+            Check::new(
+                "templates",
+                Tera::new(&settings.templates_dir.to_string_lossy())
+                    .map_err(Error::from)
+                    .and_then(|tera| {
+                        for name in ["document.org.tera", "highlights.tera"] {
+                            if !tera.get_template_names().any(|n| n == name) {
+                                return Err(Error::Config(format!(
+                                    "{} not found in {}",
+                                    name,
+                                    settings.templates_dir.display()
+                                )));
+                            }
+                        }
+                        Ok(format!("{}", settings.templates_dir.display()))
+                    }),
+            ),
+            Check::new(
+                "state file",
+                SyncState::load(&settings.state_file_path)
+                    .map(|state| format!("{} documents synced", state.documents.len())),
+            ),
+            Check::new(
+                "local copy",
+                ["highlight", "note"]
+                    .into_iter()
+                    .chain(settings.document_categories.iter().map(String::as_str))
+                    .map(|category| {
+                        Cache::new(settings.cache_dir.clone())
+                            .load(category)
+                            .map(|items| format!("{} {}s", items.len(), category))
+                    })
+                    .collect::<Result<Vec<String>>>()
+                    .map(|counts| counts.join(", ")),
+            ),
+            Check::new(
+                "updated_after",
+                get_updated_after(&settings.updated_after_file_path).map(|date| {
+                    date.unwrap_or_else(|| "none, the next sync is a full one".to_string())
+                }),
+            ),
+            Check::new("API token", token),
+        ];
+        if settings.git.auto_commit {
+            checks.push(Check::new(
+                "git",
+                git::repo_root(&settings.org_roam_dir)
+                    .map(|root| format!("committing to {}", root.display())),
+            ));
+        }
+        let api = match client {
+            Some(client) => client
+                .count(&list_query("highlight", None))
+                .await
+                .map(|count| format!("{} reachable, {} highlights", settings.api_base_url, count)),
+            None => Err(Error::Config(
+                "Not checked, since the API token couldn't be loaded".to_string(),
+            )),
+        };
+        checks.push(Check::new("Reader API", api));
+        checks
+    }
+}
+
+fn parse_cursor_date(date: &str) -> Result<DateTime<Utc>> {
+    date.parse::<DateTime<Utc>>()
+        .or_else(|_| {
+            chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .map(|d| d.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc())
+        })
+        .map_err(|e| Error::Parse(format!("Invalid date {:?}: {}", date, e)))
+}
+
+fn check_parse_failures(
+    settings: &Settings,
+    failures: &[ParseFailure],
+    options: RunOptions,
+) -> Result<()> {
+    if options.strict && !failures.is_empty() {
+        if !options.dry_run {
+            write_rejects(settings, failures)?;
+        }
+        let mut message = format!("{} items failed to parse (--strict):", failures.len());
+        for failure in failures {
+            message.push_str(&format!("\n- {}", failure));
+        }
+        return Err(Error::Parse(message));
+    }
+    Ok(())
+}
+
+fn write_rejects(settings: &Settings, failures: &[ParseFailure]) -> Result<()> {
+    // Write the items that failed to parse to the rejects file if there is one (it only ever holds
+    // the failures of the last run)
+    let Some(path) = &settings.rejects_file_path else {
+        return Ok(());
+    };
+    let mut lines = String::new();
+    for failure in failures {
+        lines.push_str(&serde_json::to_string(failure)?);
+        lines.push('\n');
+    }
+    std::fs::write(path, lines).map_err(Error::io(path))
+}
+
+enum WriteOutcome {
+    Created,
+    Edited,
+    Skipped,
+}
+
+fn write_documents(
+    writer: &mut Writer,
+    documents: &[Document],
+    highlights: Vec<Highlight>,
+    notes: Vec<Note>,
+    summary: &mut RunSummary,
+) {
+    // Create or edit the org file of each document, with its highlights and notes.
+    // A document that fails doesn't stop the others, it's reported in the summary.
+    let highlights_by_parent = map_parents_to_highlights(documents.to_vec(), highlights);
+    let notes_by_parent = note_list_to_map(notes);
+
+    let duplicate_titles = get_duplicate_titles(documents);
+    debug!("Duplicate titles: {:?}", duplicate_titles);
+
+    for (parent_id, highlights) in &highlights_by_parent {
+        let Some(parent) = documents.iter().find(|d| &d.id == parent_id) else {
+            continue;
+        };
+        let outcome = write_document(
+            writer,
+            parent,
+            highlights,
+            &notes_by_parent,
+            &duplicate_titles,
+        );
+        match outcome {
+            Ok(WriteOutcome::Created) => summary.files_created += 1,
+            Ok(WriteOutcome::Edited) => summary.files_edited += 1,
+            Ok(WriteOutcome::Skipped) => summary.files_skipped += 1,
+            Err(error) => {
+                warn!(
+                    "Failed to write {} ({}): {}",
+                    parent.title, parent.id, error
+                );
+                summary.add_failure(DocumentFailure {
+                    id: parent.id.clone(),
+                    title: parent.title.clone(),
+                    error,
+                });
+            }
+        }
+    }
+}
+
+fn write_document(
+    writer: &mut Writer,
+    parent: &Document,
+    highlights: &[Highlight],
+    notes_by_parent: &HashMap<String, Note>,
+    duplicate_titles: &[String],
+) -> Result<WriteOutcome> {
+    // Create or edit the org file of a document, and record it in the sync state
+    let Writer {
+        settings,
+        tera,
+        existing_refs,
+        sync_state,
+        checkpoint,
+        files,
+    } = writer;
+    let dry_run = files.dry_run;
+    let highlights_with_notes = get_highlights_with_notes(highlights, notes_by_parent);
+
+    let highlight_content = generate_highlight_content(&highlights_with_notes, tera)?;
+
+    // Skip the document if an interrupted sync already wrote it, and nothing changed upstream since
+    let fingerprint = document_fingerprint(parent, &highlight_content);
+    if let Some(document_state) = checkpoint
+        .as_ref()
+        .and_then(|c| c.completed(&parent.id, &fingerprint))
+    {
+        if let Some(git) = &files.git {
+            git.check(Path::new(&document_state.file))?;
+        }
+        files.describe(|| format!("Written by an interrupted sync: {}", parent.title));
+        sync_state
+            .documents
+            .insert(parent.id.clone(), document_state.clone());
+        return Ok(WriteOutcome::Skipped);
+    }
+
+    // The state of previous runs is the primary lookup, refs found in the collection are the fallback
+    let existing_file = sync_state
+        .file_for(&parent.id)
+        .map(String::from)
+        .or_else(|| find_existing_file(existing_refs, parent).cloned());
+
+    let (filename, outcome) = if let Some(filename) = existing_file {
+        let previous_state = sync_state.documents.get(&parent.id);
+        check_not_open_in_emacs(Path::new(&filename))?;
+        edit_file(
+            settings,
+            &filename,
+            parent,
+            &highlight_content,
+            previous_state,
+            files,
+        )?;
+        info!(
+            "{} file: {}",
+            if dry_run { "Would edit" } else { "Edited" },
+            filename
+        );
+        (filename, WriteOutcome::Edited)
+    } else {
+        let org_roam_dir = &settings.org_roam_dir;
+        let filename = if duplicate_titles.contains(&parent.title) {
+            get_new_entry_filename(org_roam_dir, &parent.title, Some(&parent.source_url))
+        } else {
+            get_new_entry_filename(org_roam_dir, &parent.title, None)
+        };
+
+        let content = generate_file_content(parent, &highlight_content, tera)?;
+        files.write(Path::new(&filename), None, &content)?;
+        info!(
+            "{} file: {}",
+            if dry_run { "Would create" } else { "Created" },
+            filename
+        );
+        (filename, WriteOutcome::Created)
+    };
+    files.describe(|| {
+        format!(
+            "{} {} ({} highlights)",
+            match outcome {
+                WriteOutcome::Created => "Created",
+                _ => "Edited",
+            },
+            parent.title,
+            highlights.len()
+        )
+    });
+
+    let highlight_ids = highlights.iter().map(|h| h.id.clone()).collect();
+    sync_state.record(parent, &filename, &highlight_content, highlight_ids);
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.record(&parent.id, &fingerprint, &sync_state.documents[&parent.id])?;
+    }
+    Ok(outcome)
+}
+
+fn document_fingerprint(document: &Document, highlight_content: &str) -> String {
+    // Any upstream change to a document updates its updated_at date, or changes its highlights section
+    let updated_at = document
+        .updated_at
+        .map(|u| u.to_rfc3339())
+        .unwrap_or_default();
+    format!(
+        "{:x}",
+        md5::compute(format!("{}\n{}", updated_at, highlight_content))
+    )
+}
+
+fn find_existing_file<'a>(
+    existing_refs: &'a HashMap<String, String>,
+    document: &Document,
+) -> Option<&'a String> {
+    // Return the file of the first ref of this document found in the collection
+    document
+        .ref_candidates()
+        .iter()
+        .find_map(|candidate| existing_refs.get(candidate))
+}
+
+fn get_new_entry_filename(org_roam_dir: &Path, title: &str, url: Option<&str>) -> String {
+    // Generate a new filename for a new org-roam entry, based on the title.
+    // If the URL is provided, also include the first 8 characters of the MD5 hash of the URL in the filename.
+    let now = chrono::Local::now();
+    let slug = slug::slugify(title);
+    let truncated_slug = if slug.len() > 100 {
+        slug[..100].to_string()
+    } else {
+        slug
+    };
+
+    let maybe_url_part = if let Some(u) = url {
+        let hash = md5::compute(u);
+        let hash_str = format!("{:08x}", hash);
+        let truncated_hash = &hash_str[..8];
+        format!("-{}", truncated_hash)
+    } else {
+        String::new()
+    };
+    org_roam_dir
+        .join(format!(
+            "{}-{}{}.org",
+            now.format("%Y%m%d%H%M%S"),
+            truncated_slug,
+            maybe_url_part
+        ))
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn get_duplicate_titles(documents: &[Document]) -> Vec<String> {
+    // Return a list of titles that appear more than once in the document list
+    let mut title_counts: HashMap<String, u32> = HashMap::new();
+    for document in documents {
+        *title_counts.entry(document.title.clone()).or_default() += 1;
+    }
+    title_counts
+        .iter()
+        .filter(|(_, count)| **count > 1)
+        .map(|(title, _)| title.clone())
+        .collect()
+}
+
+fn get_highlights_with_notes(
+    highlights: &[Highlight],
+    notes_by_parent: &HashMap<String, Note>,
+) -> Vec<serde_json::Value> {
+    highlights
+        .iter()
+        .rev() // Reverse the order of highlights so they end up in the correct order in the org file
+        .map(|highlight| {
+            let note = notes_by_parent.get(&highlight.id);
+            serde_json::json!({
+                "id": highlight.id,
+                "content": highlight.content,
+                "note": note.map(|n| n.content.clone()),
+                "note_saved_at": note.map(|n| n.saved_at.format("%Y-%m-%d").to_string()),
+            })
+        })
+        .collect()
+}
+
+fn generate_highlight_content(
+    highlights_with_notes: &Vec<serde_json::Value>,
+    tera: &Tera,
+) -> Result<String, tera::Error> {
+    // Generate the highlight and note section as a string
+    if highlights_with_notes.is_empty() {
+        return Ok(String::new());
+    }
+    let mut highlight_context = Context::new();
+    highlight_context.insert("highlights", highlights_with_notes);
+    tera.render("highlights.tera", &highlight_context)
+}
+
+fn generate_file_content(
+    document: &Document,
+    highlight_content: &str,
+    tera: &Tera,
+) -> Result<String, tera::Error> {
+    let uuid = uuid::Uuid::new_v4().to_string();
+
+    let mut context = Context::new();
+    context.insert("uuid", &uuid);
+    context.insert("roam_ref", &document.roam_ref);
+    if document.has_url {
+        context.insert("full_url", &document.source_url);
+    }
+    context.insert("readwise_url", &document.readwise_url);
+    context.insert("title", &document.title);
+    // The default template always has an author line, even when the author is unknown
+    context.insert("author", document.author.as_deref().unwrap_or(""));
+    context.insert(
+        "saved_at",
+        &document.saved_at.format("%Y-%m-%d %a").to_string(),
+    );
+    if let Some(published_date) = document.published_date {
+        context.insert(
+            "published_date",
+            &published_date.format("%Y-%m-%d").to_string(),
+        );
+    }
+    context.insert(
+        "read_status",
+        read_status_by_location(document.location.as_str()),
+    );
+    // Not used by the default template, but available to custom ones
+    context.insert("tags", &document.tags);
+    context.insert("summary", &document.summary);
+    context.insert("site_name", &document.site_name);
+    context.insert("word_count", &document.word_count);
+    context.insert("reading_progress", &document.reading_progress);
+    context.insert("image_url", &document.image_url);
+    context.insert("highlight_content", highlight_content);
+    tera.render("document.org.tera", &context)
+}
+
+fn edit_file(
+    settings: &Settings,
+    filename: &str,
+    parent: &Document,
+    highlight_content: &str,
+    previous_state: Option<&DocumentState>,
+    files: &mut Files,
+) -> Result<()> {
+    // Read all lines from file
+    let content = std::fs::read_to_string(filename).map_err(Error::io(filename))?;
+    let mut lines: Vec<_> = content.lines().collect();
+
+    // Find index where highlights section starts
+    let highlight_index = org::find_subtree(&lines, org::HIGHLIGHTS_HEADING)
+        .map(|(start, _)| start)
+        .unwrap_or(lines.len());
+
+    // Update read status, only looking before the highlights section
+    let read_status_line = format!(
+        "- read status: {}",
+        read_status_by_location(parent.location.as_str())
+    );
+    if let Some(pos) = lines[..highlight_index]
+        .iter()
+        .position(|line| line.trim().starts_with("- read status:"))
+    {
+        lines[pos] = read_status_line.as_str();
+    }
+    let mut updated_content = lines.join("\n");
+    if content.ends_with('\n') {
+        updated_content.push('\n');
+    }
+
+    // Find the highlights we previously wrote that were deleted upstream.
+    // Without a saved state, fall back to the highlights currently in the file.
+    let current_ids = org::highlight_ids(highlight_content);
+    let deleted_ids: Vec<String> = previous_state
+        .map(|d| d.highlight_ids.clone())
+        .unwrap_or_else(|| org::highlight_ids(&content))
+        .into_iter()
+        .filter(|id| !current_ids.contains(id))
+        .collect();
+    if !deleted_ids.is_empty() {
+        info!(
+            "{} highlight(s) deleted in Readwise from {}: {:?}",
+            deleted_ids.len(),
+            filename,
+            deleted_ids
+        );
+    }
+    let (previous_content, deleted_blocks) = match settings.deleted_highlights {
+        DeletedHighlightsPolicy::Drop => (content.clone(), Vec::new()),
+        _ => org::take_highlight_blocks(&content, &deleted_ids),
+    };
+
+    // Re-attach the annotations written inside each highlight to the freshly rendered section
+    let (mut highlight_content, orphans) = if settings.preserve_annotations {
+        org::merge_annotations(
+            &previous_content,
+            highlight_content,
+            previous_state.and_then(|d| d.highlight_content.as_deref()),
+        )
+    } else {
+        (highlight_content.to_string(), Vec::new())
+    };
+
+    // Commented out highlights stay at the end of the highlights section
+    if settings.deleted_highlights == DeletedHighlightsPolicy::Comment {
+        let mut commented = org::commented_blocks(&content);
+        for block in &deleted_blocks {
+            commented.extend(org::comment_out(block));
+        }
+        if !commented.is_empty() {
+            if highlight_content.is_empty() {
+                highlight_content = format!("* {}", org::HIGHLIGHTS_HEADING);
+            }
+            highlight_content.push('\n');
+            highlight_content.push_str(&commented.join("\n"));
+        }
+    }
+
+    // Replace only the highlights subtree, keeping any heading that comes after it
+    let new_content = org::replace_subtree(
+        &updated_content,
+        org::HIGHLIGHTS_HEADING,
+        &highlight_content,
+    );
+    let new_content = org::append_orphans(&new_content, &orphans);
+    let new_content = if settings.deleted_highlights == DeletedHighlightsPolicy::Move {
+        org::append_deleted(&new_content, &deleted_blocks.concat())
+    } else {
+        new_content
+    };
+
+    // Write back to file
+    files.write(Path::new(filename), Some(&content), &new_content)
+}
+
+async fn handle_deleted_documents(
+    fetcher: &Fetcher<'_>,
+    writer: &mut Writer<'_>,
+    summary: &mut RunSummary,
+) -> Result<()> {
+    // Tag or move the files of the documents we synced before that no longer exist upstream.
+    // A file that can't be tagged or moved stays in the state, to be retried on the next run.
+    // Only the documents of the categories fetched are considered: a category removed from the settings
+    // isn't a deletion. Neither is a document whose category wasn't recorded by the run that synced it.
+    let settings = fetcher.settings();
+    let sync_state = &mut writer.sync_state;
+    if settings.deleted_documents == DeletedDocumentsPolicy::Ignore {
+        return Ok(());
+    }
+    let existing_ids = fetcher.get_document_ids().await?;
+    let deleted_ids: Vec<String> = sync_state
+        .documents
+        .iter()
+        .filter(|(_, document)| {
+            document
+                .category
+                .as_ref()
+                .is_some_and(|c| settings.document_categories.contains(c))
+        })
+        .map(|(id, _)| id)
+        .filter(|id| !existing_ids.contains(*id))
+        .cloned()
+        .collect();
+    for id in deleted_ids {
+        let file = sync_state.documents[&id].file.clone();
+        match retire_deleted_document_file(settings, Path::new(&file), &mut writer.files) {
+            Ok(()) => {
+                sync_state.documents.remove(&id);
+            }
+            Err(error) => {
+                warn!("Failed to handle deleted document {}: {}", id, error);
+                summary.add_failure(DocumentFailure {
+                    id,
+                    title: file,
+                    error,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_not_open_in_emacs(path: &Path) -> Result<()> {
+    // Rewriting a file with unsaved changes in Emacs would make us and Emacs overwrite each other
+    match emacs_lock(path) {
+        Some(lock) => Err(Error::OpenInEmacs {
+            path: path.to_path_buf(),
+            lock,
+        }),
+        None => Ok(()),
+    }
+}
+
+fn retire_deleted_document_file(settings: &Settings, path: &Path, files: &mut Files) -> Result<()> {
+    if !path.is_file() {
+        return Ok(());
+    }
+    check_not_open_in_emacs(path)?;
+    match settings.deleted_documents {
+        DeletedDocumentsPolicy::Ignore => {}
+        DeletedDocumentsPolicy::Tag => {
+            let content = std::fs::read_to_string(path).map_err(Error::io(path))?;
+            let tagged = org::add_filetag(&content, "readwise_deleted");
+            files.write(path, Some(&content), &tagged)?;
+            files.describe(|| format!("Tagged {} as deleted in Readwise", path.display()));
+            info!("Tagged file of deleted document: {}", path.display());
+        }
+        DeletedDocumentsPolicy::Move => {
+            let deleted_documents_dir = &settings.deleted_documents_dir;
+            let destination = unused_path(deleted_documents_dir, path);
+            if files.dry_run {
+                files.changes.push(format!(
+                    "Would move file of deleted document: {} -> {}\n",
+                    path.display(),
+                    destination.display()
+                ));
+                return Ok(());
+            }
+            std::fs::create_dir_all(deleted_documents_dir)
+                .map_err(Error::io(deleted_documents_dir))?;
+            files.rename(path, &destination)?;
+            files.describe(|| format!("Moved {} as deleted in Readwise", path.display()));
+            info!(
+                "Moved file of deleted document: {} -> {}",
+                path.display(),
+                destination.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn unused_path(dir: &Path, path: &Path) -> PathBuf {
+    // Return the path of a file of the same name in dir, or with a -1, -2... suffix if there's already one,
+    // so that moving a file there never replaces another one
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let (stem, extension) = match file_name.split_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (file_name.as_ref(), String::new()),
+    };
+    (0..)
+        .map(|n| match n {
+            0 => dir.join(file_name.as_ref()),
+            n => dir.join(format!("{}-{}{}", stem, n, extension)),
+        })
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+fn read_status_by_location(location: &str) -> &str {
+    if location == "archive" {
+        "DONE"
+    } else {
+        "TODO"
+    }
+}
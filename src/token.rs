@@ -0,0 +1,88 @@
+use crate::error::{Error, Result};
+use crate::settings::TokenSource;
+
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+// The Readwise API token. It's never printed: its Debug output is redacted, and errors about it never
+// include it.
+pub struct Token(String);
+
+impl Token {
+    pub fn new(token: &str) -> Self {
+        Self(token.to_string())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Token(<redacted>)")
+    }
+}
+
+pub fn load(source: &TokenSource, config_dir: &Path) -> Result<Token> {
+    let token = match source {
+        TokenSource::Env { var } => {
+            // The variable can also be set in a .env file in the config directory
+            dotenv::from_path(config_dir.join(".env")).ok();
+            std::env::var(var).map_err(|_| Error::Config(format!("{} is not set", var)))?
+        }
+        TokenSource::File { path } => read_token_file(path)?,
+        TokenSource::Command { command } => run_token_command(command)?,
+    };
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(Error::Config("The API token is empty".to_string()));
+    }
+    Ok(Token(token.to_string()))
+}
+
+fn read_token_file(path: &Path) -> Result<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path)
+            .map_err(Error::io(path))?
+            .permissions()
+            .mode();
+        if mode & 0o007 != 0 {
+            return Err(Error::Config(format!(
+                "{} is accessible to other users (mode {:o}), run `chmod 600` on it",
+                path.display(),
+                mode & 0o777
+            )));
+        }
+    }
+    std::fs::read_to_string(path).map_err(Error::io(path))
+}
+
+fn run_token_command(command: &[String]) -> Result<String> {
+    // e.g. ["pass", "show", "readwise"]: the token is the first line of the output
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| Error::Config("The token command is empty".to_string()))?;
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| Error::Config(format!("Failed to run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(Error::Config(format!(
+            "The token command {} failed ({}): {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let stdout = String::from_utf8(output.stdout).map_err(|_| {
+        Error::Config(format!(
+            "The output of the token command {} isn't valid UTF-8",
+            program
+        ))
+    })?;
+    Ok(stdout.lines().next().unwrap_or_default().to_string())
+}
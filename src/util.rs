@@ -1,19 +1,23 @@
-use crate::settings::SETTINGS;
+use crate::error::{Error, Result};
 use reqwest::Url;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
-pub fn clean_url(url: &str) -> String {
-    // Clean the URL of its query parameters, except for those that are in the SETTINGS.keep_query_params list for this domain.
-    let mut parsed_url = Url::parse(url).unwrap();
+pub fn clean_url(url: &str, keep_query_params: &HashMap<String, Vec<String>>) -> Result<String> {
+    // Clean the URL of its query parameters, except for those that are in the keep_query_params list for this domain.
+    let mut parsed_url =
+        Url::parse(url).map_err(|e| Error::Parse(format!("Invalid URL {:?}: {}", url, e)))?;
     let host = parsed_url.host_str().unwrap_or("");
 
     // Check if we have rules for this domain
-    let matching_domain = SETTINGS
-        .keep_query_params
+    let matching_domain = keep_query_params
         .keys()
         .find(|&domain| host.ends_with(domain));
 
     if let Some(domain) = matching_domain {
-        if let Some(allowed_params) = SETTINGS.keep_query_params.get(domain) {
+        if let Some(allowed_params) = keep_query_params.get(domain) {
             // Build new query string with only allowed parameters
             let params: Vec<(String, String)> = parsed_url
                 .query_pairs()
@@ -31,5 +35,83 @@ pub fn clean_url(url: &str) -> String {
         parsed_url.set_query(None);
     }
     parsed_url.set_fragment(None);
-    parsed_url.to_string()
+    Ok(parsed_url.to_string())
+}
+
+pub fn write_atomically(path: &Path, content: impl AsRef<[u8]>) -> Result<()> {
+    // Write to a temporary file next to path, flush it to disk, then rename it over path, so that path
+    // always holds either its previous or its new content, even if the process is killed mid-write.
+    // A symlink is followed, so that the file it points to is replaced rather than the symlink, and the
+    // permissions of the file are kept.
+    let target = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let permissions = fs::metadata(&target).ok().map(|m| m.permissions());
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(target.file_name().unwrap_or_default());
+    temp_name.push(".tmp");
+    let temp_path = target.with_file_name(temp_name);
+    fs::File::create(&temp_path)
+        .and_then(|mut file| {
+            if let Some(permissions) = permissions {
+                file.set_permissions(permissions)?;
+            }
+            file.write_all(content.as_ref())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, &target))
+        .map_err(|e| {
+            fs::remove_file(&temp_path).ok();
+            Error::io(path)(e)
+        })?;
+    // Make the rename itself durable
+    if let Some(parent) = target.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::File::open(parent).and_then(|dir| dir.sync_all()).ok();
+    }
+    Ok(())
+}
+
+pub fn emacs_lock(path: &Path) -> Option<PathBuf> {
+    // Return the lock file (.#name, usually a dangling symlink) or the autosave file (#name#) that Emacs
+    // keeps next to a file while its buffer has unsaved changes, if there's one
+    let name = path.file_name()?.to_string_lossy();
+    [format!(".#{}", name), format!("#{}#", name)]
+        .into_iter()
+        .map(|lock_name| path.with_file_name(lock_name))
+        .find(|lock| fs::symlink_metadata(lock).is_ok())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_write_atomically_keeps_permissions_and_symlinks() {
+        let dir = std::env::temp_dir().join(format!(
+            "org-readwise-rust-test-write-{}",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(dir.join("notes")).unwrap();
+        let target = dir.join("notes/note.org");
+        fs::write(&target, "before").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o600)).unwrap();
+        let link = dir.join("note.org");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        write_atomically(&link, "after").unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "after");
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // The temporary file was created next to the target, and renamed
+        assert_eq!(fs::read_dir(dir.join("notes")).unwrap().count(), 1);
+
+        // A new file is created
+        write_atomically(&dir.join("new.org"), "new").unwrap();
+        assert_eq!(fs::read_to_string(dir.join("new.org")).unwrap(), "new");
+        fs::remove_dir_all(&dir).ok();
+    }
 }
@@ -0,0 +1,92 @@
+#[allow(dead_code)]
+mod common;
+
+use common::{FakeReader, TestEnv};
+
+fn stdout(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn test_status_and_reset_cursor() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("cli-status", &server.base_url, "");
+    let output = env.run(&["status"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("Next sync fetches everything"));
+    assert!(stdout(&output).contains("Last run: never"));
+    assert!(stdout(&output).contains("- article: 3\n"));
+
+    assert!(env.run(&["sync"]).status.success());
+    let output = env.run(&["status"]);
+    let status = stdout(&output);
+    assert!(status.contains("Next sync fetches what was updated after 20"));
+    assert!(status.contains("Last run: sync at "));
+    assert!(status.contains("3 files created"));
+    assert!(status.contains("Synced documents: 3\n"));
+    assert!(status.contains("- article: 0\n- highlight: 0\n- note: 0\n"));
+
+    let output = env.run(&["reset-cursor", "--to", "2024-12-03"]);
+    assert!(output.status.success());
+    let updated_after = env.config_dir.join("updated_after.txt");
+    assert_eq!(
+        std::fs::read_to_string(&updated_after).unwrap(),
+        "2024-12-03T00:00:00.000Z"
+    );
+    assert!(stdout(&env.run(&["status"])).contains("- article: 2\n"));
+
+    let output = env.run(&["reset-cursor", "--to", "yesterday"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid date \"yesterday\""));
+
+    assert!(env.run(&["reset-cursor"]).status.success());
+    assert!(!updated_after.exists());
+}
+
+#[test]
+fn test_list_doctor_and_global_flags() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("cli-list", &server.base_url, "");
+
+    // The config file can be anywhere, relative paths are relative to its directory
+    let config_path = env.root.join("elsewhere/config.toml");
+    std::fs::create_dir_all(config_path.parent().unwrap()).unwrap();
+    std::fs::rename(env.config_dir.join("config.toml"), &config_path).unwrap();
+    let config = config_path.to_str().unwrap();
+    assert_eq!(env.run(&["sync"]).status.code(), Some(1));
+
+    // Only warnings, errors and the summary with --quiet
+    let output = env.run(&["--quiet", "--config", config]);
+    assert!(output.status.success());
+    assert!(!stdout(&output).contains("Created file"));
+    assert!(stdout(&output).contains("Created 3 files"));
+    assert!(env.root.join("elsewhere/updated_after.txt").exists());
+
+    // Each request is printed with --verbose
+    let output = env.run(&["status", "-v", "--config", config]);
+    assert!(stdout(&output).contains("Fetching "));
+
+    let output = env.run(&["list", "--config", config]);
+    assert!(output.status.success());
+    let list = stdout(&output);
+    let lines: Vec<&str> = list.lines().collect();
+    assert_eq!(lines.len(), 3);
+    // Most recently updated first, with the file written for each document
+    assert!(lines[0].starts_with("01doc0noauthor00000000000\tAn anonymous article\t"));
+    assert!(lines[0].ends_with(".org"));
+
+    let output = env.run(&["doctor", "--config", config]);
+    assert!(output.status.success());
+    let doctor = stdout(&output);
+    assert!(doctor.contains("ok    state file: 3 documents synced"));
+    assert!(doctor.contains("ok    Reader API: "));
+
+    let output = env
+        .command(&["doctor", "--config", config])
+        .env_remove("READWISE_API_KEY")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output)
+        .contains("FAIL  API token: Configuration error: READWISE_API_KEY is not set"));
+}
@@ -0,0 +1,284 @@
+// A local stand-in for the Reader API list endpoint, serving items from a fixture, and helpers to run
+// the binary against it with a temporary config directory and org-roam directory.
+
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+
+pub const API_KEY: &str = "test-api-key";
+const PAGE_SIZE: usize = 2;
+
+// A response that isn't computed from the items: status, extra headers and body
+type CannedResponse = (&'static str, Vec<String>, String);
+
+#[derive(Default)]
+struct ServerState {
+    items: Vec<Value>,
+    // Responses given to the upcoming requests, before answering normally again
+    canned_responses: VecDeque<CannedResponse>,
+    requests: Vec<String>,
+    user_agents: Vec<String>,
+}
+
+pub struct FakeReader {
+    pub base_url: String,
+    state: Arc<Mutex<ServerState>>,
+}
+
+impl FakeReader {
+    pub fn start(items: Vec<Value>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/api/v3", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(ServerState {
+            items,
+            ..Default::default()
+        }));
+        let server_state = state.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                handle_connection(stream, &server_state);
+            }
+        });
+        Self { base_url, state }
+    }
+
+    pub fn with_fixture() -> Self {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/reader_items.json");
+        let items: Vec<Value> =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        Self::start(items)
+    }
+
+    pub fn throttle_next_requests(&self, count: usize) {
+        // Like Readwise, only say how long to wait in the body
+        let body = json!({"detail": "Request was throttled. Expected available in 0 seconds."});
+        for _ in 0..count {
+            self.respond_next_with("429 Too Many Requests", &[], &body.to_string());
+        }
+    }
+
+    pub fn respond_next_with(&self, status: &'static str, headers: &[&str], body: &str) {
+        self.state.lock().unwrap().canned_responses.push_back((
+            status,
+            headers.iter().map(|h| h.to_string()).collect(),
+            body.to_string(),
+        ));
+    }
+
+    pub fn upsert(&self, item: Value) {
+        // Add an item, or replace the item with the same id
+        let mut state = self.state.lock().unwrap();
+        state.items.retain(|i| i["id"] != item["id"]);
+        state.items.push(item);
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.state.lock().unwrap().items.retain(|i| i["id"] != id);
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn user_agents(&self) -> Vec<String> {
+        self.state.lock().unwrap().user_agents.clone()
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, state: &Mutex<ServerState>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    let mut authorized = false;
+    let mut user_agent = String::new();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).is_err() || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization")
+                && value.trim() == format!("Token {}", API_KEY)
+            {
+                authorized = true;
+            }
+            if name.eq_ignore_ascii_case("user-agent") {
+                user_agent = value.trim().to_string();
+            }
+        }
+    }
+    let target = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("/")
+        .to_string();
+    state.lock().unwrap().user_agents.push(user_agent);
+    let (status, headers, body) = respond(&target, authorized, state);
+    let headers: String = headers.iter().map(|h| format!("{}\r\n", h)).collect();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        headers,
+        body
+    );
+    stream.write_all(response.as_bytes()).ok();
+}
+
+fn respond(target: &str, authorized: bool, state: &Mutex<ServerState>) -> CannedResponse {
+    let mut state = state.lock().unwrap();
+    state.requests.push(target.to_string());
+    if !authorized {
+        return (
+            "401 Unauthorized",
+            Vec::new(),
+            json!({"detail": "Invalid token."}).to_string(),
+        );
+    }
+    if let Some(response) = state.canned_responses.pop_front() {
+        return response;
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if !path.ends_with("/list/") {
+        return (
+            "404 Not Found",
+            Vec::new(),
+            json!({"detail": "Not found."}).to_string(),
+        );
+    }
+    let params: Vec<(&str, &str)> = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .collect();
+    let param = |key: &str| params.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+
+    let mut matching: Vec<&Value> = state
+        .items
+        .iter()
+        .filter(|item| param("category").is_none_or(|c| item["category"] == c))
+        .filter(|item| param("id").is_none_or(|id| item["id"] == id))
+        .filter(|item| {
+            param("updatedAfter")
+                .is_none_or(|after| item["updated_at"].as_str().unwrap_or("") > after)
+        })
+        .collect();
+    // Like the real API, most recently updated first
+    matching.sort_by(|a, b| b["updated_at"].as_str().cmp(&a["updated_at"].as_str()));
+
+    let offset: usize = param("pageCursor")
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(0);
+    let page: Vec<&Value> = matching
+        .iter()
+        .skip(offset)
+        .take(PAGE_SIZE)
+        .copied()
+        .collect();
+    let next_cursor = if offset + PAGE_SIZE < matching.len() {
+        Value::from((offset + PAGE_SIZE).to_string())
+    } else {
+        Value::Null
+    };
+    (
+        "200 OK",
+        Vec::new(),
+        json!({"count": matching.len(), "nextPageCursor": next_cursor, "results": page})
+            .to_string(),
+    )
+}
+
+pub struct TestEnv {
+    pub root: PathBuf,
+    pub home: PathBuf,
+    pub config_dir: PathBuf,
+    pub org_roam_dir: PathBuf,
+}
+
+impl TestEnv {
+    pub fn new(name: &str, base_url: &str, extra_config: &str) -> Self {
+        let root =
+            std::env::temp_dir().join(format!("org-readwise-rust-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&root).ok();
+        let home = root.join("home");
+        let config_dir = home.join(".config/org-readwise-rust");
+        let org_roam_dir = root.join("roam");
+        std::fs::create_dir_all(&config_dir).unwrap();
+        std::fs::create_dir_all(&org_roam_dir).unwrap();
+        let templates_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("templates/**/*");
+        let config = format!(
+            r#"org_roam_dir = "{}"
+templates_dir = "{}"
+updated_after_file_path = "updated_after.txt"
+document_categories = ["epub", "article"]
+api_base_url = "{}"
+{}
+
+[keep_query_params]
+"youtube.com" = ["v"]
+
+# Don't slow the tests down
+[rate_limit]
+requests_per_minute = 60000
+burst = 100
+"#,
+            org_roam_dir.display(),
+            templates_dir.display(),
+            base_url,
+            extra_config
+        );
+        std::fs::write(config_dir.join("config.toml"), config).unwrap();
+        Self {
+            root,
+            home,
+            config_dir,
+            org_roam_dir,
+        }
+    }
+
+    pub fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_org-readwise-rust"));
+        command
+            .args(args)
+            .env("HOME", &self.home)
+            .env("READWISE_API_KEY", API_KEY)
+            .env("NO_PROXY", "127.0.0.1,localhost");
+        command
+    }
+
+    pub fn run(&self, args: &[&str]) -> Output {
+        let output = self.command(args).output().unwrap();
+        println!("{}", String::from_utf8_lossy(&output.stdout));
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        output
+    }
+
+    pub fn org_files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(&self.org_roam_dir)
+            .unwrap()
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|e| e == "org"))
+            .collect();
+        files.sort();
+        files
+    }
+
+    pub fn org_file_containing(&self, needle: &str) -> PathBuf {
+        self.org_files()
+            .into_iter()
+            .find(|f| std::fs::read_to_string(f).unwrap().contains(needle))
+            .unwrap_or_else(|| panic!("No org file contains {:?}", needle))
+    }
+}
+
+impl Drop for TestEnv {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.root).ok();
+    }
+}
@@ -0,0 +1,66 @@
+#[allow(dead_code)]
+mod common;
+
+use common::{FakeReader, API_KEY};
+use org_readwise_rust::{ReadwiseClient, RunOptions, Settings, Syncer, Token};
+use std::path::Path;
+
+#[tokio::test]
+async fn test_syncer_with_in_memory_config() {
+    let server = FakeReader::with_fixture();
+    let root =
+        std::env::temp_dir().join(format!("org-readwise-rust-library-{}", std::process::id()));
+    std::fs::remove_dir_all(&root).ok();
+    std::fs::create_dir_all(root.join("roam")).unwrap();
+    let templates_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("templates/**/*");
+    let settings = Settings::from_toml(
+        &format!(
+            r#"org_roam_dir = "roam"
+templates_dir = "{}"
+updated_after_file_path = "updated_after.txt"
+document_categories = ["epub", "article"]
+rate_limit = {{ requests_per_minute = 60000, burst = 100 }}
+api_base_url = "{}"
+"#,
+            templates_dir.display(),
+            server.base_url
+        ),
+        None,
+    )
+    .unwrap();
+    // The token doesn't come from the environment, and the client doesn't use any proxy
+    let client = ReadwiseClient::new(
+        &settings,
+        reqwest::Client::builder().no_proxy().build().unwrap(),
+        Token::new(API_KEY),
+    );
+    let syncer = Syncer::new(settings).with_root(&root).with_client(client);
+    assert_eq!(syncer.settings().org_roam_dir, root.join("roam"));
+
+    // A dry run returns what would change, without writing or printing it
+    let dry_run = RunOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    let summary = syncer.sync(dry_run).await.unwrap();
+    assert_eq!(summary.changes.len(), 3);
+    assert!(summary.changes[0].starts_with("--- /dev/null\n"));
+    assert!(summary.next_updated_after.is_some());
+    assert_eq!(std::fs::read_dir(root.join("roam")).unwrap().count(), 0);
+
+    let summary = syncer.sync(RunOptions::default()).await.unwrap();
+    assert!(summary.changes.is_empty());
+    assert_eq!(summary.files_created, 3);
+    assert_eq!(summary.parse_failures.len(), 1);
+    assert!(summary.failed.is_empty());
+    assert_eq!(std::fs::read_dir(root.join("roam")).unwrap().count(), 3);
+    assert!(root.join("updated_after.txt").exists());
+    assert!(root.join("cache/highlight.jsonl").exists());
+
+    // Rendering only reads the local copy
+    let requests = server.requests().len();
+    let summary = syncer.render(&[], RunOptions::default()).unwrap();
+    assert_eq!(summary.files_edited, 3);
+    assert_eq!(server.requests().len(), requests);
+    std::fs::remove_dir_all(&root).ok();
+}
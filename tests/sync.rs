@@ -0,0 +1,1023 @@
+mod common;
+
+use common::{FakeReader, TestEnv};
+use serde_json::json;
+
+const ARTICLE_ID: &str = "01doc0article0000000000000";
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, false)
+}
+
+#[test]
+fn test_full_sync_creates_files() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("full-sync", &server.base_url, "");
+    assert!(env.run(&[]).status.success());
+
+    // The email isn't in document_categories
+    assert_eq!(env.org_files().len(), 3);
+
+    let article = std::fs::read_to_string(env.org_file_containing("Theses on testing")).unwrap();
+    assert!(article.contains(":ROAM_REFS: https://example.com/theses\n"));
+    assert!(article.contains("- read status: DONE"));
+    assert!(article.contains(
+        "* readwise:highlights\n** readwise:01hl0article1000000000000\nFirst highlight of the article\n*** note (2024-12-03)\nMy note on the first highlight\n** readwise:01hl0article2000000000000\nSecond highlight of the article"
+    ));
+    // Empty highlights are skipped
+    assert!(!article.contains("01hl0article3000000000000"));
+
+    let book = std::fs::read_to_string(env.org_file_containing("A book (2003)")).unwrap();
+    assert!(book.contains(":ROAM_REFS: @readwise_01doc0book000000000000000"));
+    assert!(book.contains("A highlight from the book"));
+
+    // Documents with null fields aren't dropped
+    let anonymous =
+        std::fs::read_to_string(env.org_file_containing("An anonymous article")).unwrap();
+    assert!(anonymous.contains("- author: \n"));
+    assert!(anonymous.contains("- date: 2024-01-15"));
+
+    assert!(env.config_dir.join("updated_after.txt").exists());
+}
+
+#[test]
+fn test_incremental_sync_edits_files_in_place() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("incremental-sync", &server.base_url, "");
+    assert!(env.run(&[]).status.success());
+
+    let article_file = env.org_file_containing("Theses on testing");
+    let mut content = std::fs::read_to_string(&article_file).unwrap();
+    content.push_str("\n* My thoughts\nKeep me\n");
+    std::fs::write(&article_file, content).unwrap();
+
+    // Adding a highlight in Reader marks its document as updated
+    server.upsert(json!({
+        "id": "01hl0article4000000000000",
+        "category": "highlight",
+        "parent_id": ARTICLE_ID,
+        "content": "A new highlight",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+    let mut fixture: Vec<serde_json::Value> = serde_json::from_str(
+        &std::fs::read_to_string(
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/reader_items.json"),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let mut article = fixture.remove(0);
+    article["updated_at"] = json!(now());
+    server.upsert(article);
+
+    let requests_before = server.requests().len();
+    assert!(env.run(&[]).status.success());
+    let requests = &server.requests()[requests_before..];
+    assert!(requests.iter().all(|r| r.contains("updatedAfter=")));
+
+    assert_eq!(env.org_files().len(), 3);
+    let content = std::fs::read_to_string(&article_file).unwrap();
+    assert!(content.contains(
+        "Second highlight of the article\n** readwise:01hl0article4000000000000\nA new highlight\n"
+    ));
+    assert!(content.ends_with("* My thoughts\nKeep me\n"));
+}
+
+#[test]
+fn test_hand_written_notes_are_edited_instead_of_duplicated() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("hand-written", &server.base_url, "");
+    // One note refers to the article by its cleaned source URL, the other to a document with a URL by its
+    // Readwise id, each among other refs
+    let notes = [
+        (
+            "my-theses.org",
+            ":PROPERTIES:\n:ID: 11111111-1111-1111-1111-111111111111\n:ROAM_REFS: @smith2024 \"https://example.com/theses\"\n:END:\n#+TITLE: My theses\n\nWritten by hand\n",
+        ),
+        (
+            "my-anonymous.org",
+            ":PROPERTIES:\n:ID: 22222222-2222-2222-2222-222222222222\n:ROAM_REFS: https://mirror.example.org/anonymous @readwise_01doc0noauthor00000000000\n:END:\n#+TITLE: My anonymous\n\nWritten by hand too\n",
+        ),
+    ];
+    for (name, content) in notes {
+        std::fs::write(env.org_roam_dir.join(name), content).unwrap();
+    }
+
+    let output = env.run(&[]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Edited 2 files"));
+    // Only the book got a new file
+    assert_eq!(env.org_files().len(), 3);
+    let theses = std::fs::read_to_string(env.org_roam_dir.join("my-theses.org")).unwrap();
+    assert!(theses.contains("Written by hand\n"));
+    assert!(theses.contains("First highlight of the article"));
+    let anonymous = std::fs::read_to_string(env.org_roam_dir.join("my-anonymous.org")).unwrap();
+    assert!(anonymous.contains("Written by hand too\n"));
+    assert!(env
+        .org_file_containing("A book (2003)")
+        .file_name()
+        .is_some_and(|name| name != "my-theses.org" && name != "my-anonymous.org"));
+}
+
+#[test]
+fn test_throttled_requests_are_retried() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("throttled", &server.base_url, "");
+    server.throttle_next_requests(1);
+    assert!(env.run(&[]).status.success());
+    assert_eq!(env.org_files().len(), 3);
+    // The throttled request was sent again
+    let requests = server.requests();
+    assert_eq!(requests.iter().filter(|r| **r == requests[0]).count(), 2);
+}
+
+#[test]
+fn test_retry_after_header_is_honoured() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("retry-after", &server.base_url, "");
+    server.respond_next_with("429 Too Many Requests", &["Retry-After: 1"], "{}");
+    let start = std::time::Instant::now();
+    assert!(env.run(&[]).status.success());
+    assert!(start.elapsed() >= std::time::Duration::from_secs(1));
+    assert_eq!(env.org_files().len(), 3);
+}
+
+#[test]
+fn test_server_errors_are_retried() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new(
+        "server-errors",
+        &server.base_url,
+        "retry.initial_backoff_secs = 0.01",
+    );
+    server.respond_next_with("502 Bad Gateway", &[], "Bad gateway");
+    server.respond_next_with("503 Service Unavailable", &[], "Unavailable");
+    assert!(env.run(&[]).status.success());
+    assert_eq!(env.org_files().len(), 3);
+}
+
+#[test]
+fn test_retries_are_limited() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new(
+        "retries-limited",
+        &server.base_url,
+        "retry.initial_backoff_secs = 0.01\nretry.max_retries = 1",
+    );
+    for _ in 0..10 {
+        server.respond_next_with("500 Internal Server Error", &[], "Oops");
+    }
+    let output = env.run(&[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("giving up after 1 retries"));
+    assert!(env.org_files().is_empty());
+}
+
+#[test]
+fn test_http_settings() {
+    let server = FakeReader::with_fixture();
+    // The API is only reachable through the fake server acting as a proxy
+    let proxy = server.base_url.trim_end_matches("/api/v3");
+    let env = TestEnv::new(
+        "http-settings",
+        "http://readwise.invalid/api/v3",
+        &format!(
+            "http.proxy = \"{}\"\nhttp.user_agent = \"my-sync/1.0\"",
+            proxy
+        ),
+    );
+    assert!(env.run(&[]).status.success());
+    assert_eq!(env.org_files().len(), 3);
+    let requests = server.requests();
+    assert!(requests[0].starts_with("http://readwise.invalid/api/v3/list/?"));
+    assert!(server.user_agents().iter().all(|ua| ua == "my-sync/1.0"));
+}
+
+#[test]
+fn test_invalid_ca_certificate_fails() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new(
+        "invalid-ca",
+        &server.base_url,
+        "http.ca_certificates = [\"ca.pem\"]",
+    );
+    std::fs::write(env.config_dir.join("ca.pem"), "not a certificate").unwrap();
+    let output = env.run(&[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("ca.pem"));
+    assert!(server.requests().is_empty());
+}
+
+#[test]
+fn test_render_makes_no_network_calls() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("render", &server.base_url, "");
+    assert!(env.run(&[]).status.success());
+    let book_file = env.org_file_containing("A book (2003)");
+    std::fs::remove_file(&book_file).unwrap();
+
+    let requests_before = server.requests().len();
+    server.remove(ARTICLE_ID);
+    assert!(env.run(&["render"]).status.success());
+    assert_eq!(server.requests().len(), requests_before);
+    // The article comes from the local cache, and the deleted book file was re-created
+    assert_eq!(env.org_files().len(), 3);
+    env.org_file_containing("A book (2003)");
+}
+
+#[test]
+fn test_parse_failures_are_reported() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new(
+        "parse-failures",
+        &server.base_url,
+        r#"rejects_file_path = "rejects.jsonl""#,
+    );
+    let output = env.run(&[]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("- article 01doc0notitle000000000000: Missing title"));
+    let rejects = std::fs::read_to_string(env.config_dir.join("rejects.jsonl")).unwrap();
+    let rejects: Vec<serde_json::Value> = rejects
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(rejects.len(), 1);
+    assert_eq!(rejects[0]["id"], "01doc0notitle000000000000");
+    assert_eq!(rejects[0]["category"], "article");
+}
+
+#[test]
+fn test_strict_mode_fails_on_parse_failures() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("strict", &server.base_url, "");
+    assert!(!env.run(&["--strict"]).status.success());
+    // Nothing is written, and the next run fetches everything again
+    assert!(env.org_files().is_empty());
+    assert!(!env.config_dir.join("updated_after.txt").exists());
+
+    server.remove("01doc0notitle000000000000");
+    assert!(env.run(&["--strict"]).status.success());
+    assert_eq!(env.org_files().len(), 3);
+}
+
+#[test]
+fn test_failed_document_doesnt_stop_the_others() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("partial-failure", &server.base_url, "");
+    assert!(env.run(&[]).status.success());
+    let updated_after = std::fs::read_to_string(env.config_dir.join("updated_after.txt")).unwrap();
+
+    // A file that isn't valid UTF-8 can't be edited
+    let article_file = env.org_file_containing("Theses on testing");
+    let mut content = std::fs::read(&article_file).unwrap();
+    content.extend_from_slice(b"\xff\xfe\n");
+    std::fs::write(&article_file, content).unwrap();
+    server.upsert(json!({
+        "id": "01hl0article4000000000000",
+        "category": "highlight",
+        "parent_id": ARTICLE_ID,
+        "content": "A new highlight",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+    server.upsert(json!({
+        "id": "01hl0book2000000000000000",
+        "category": "highlight",
+        "parent_id": "01doc0book000000000000000",
+        "content": "Another highlight from the book",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+
+    let output = env.run(&[]);
+    assert_eq!(output.status.code(), Some(2));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("1 documents failed:\n- Theses on testing (01doc0article0000000000000)")
+    );
+    let book = std::fs::read_to_string(env.org_file_containing("A book (2003)")).unwrap();
+    assert!(book.contains("Another highlight from the book"));
+    // The failed document is retried on the next run
+    assert_eq!(
+        std::fs::read_to_string(env.config_dir.join("updated_after.txt")).unwrap(),
+        updated_after
+    );
+    let checkpoint = env.config_dir.join("updated_after.txt.progress.jsonl");
+    assert!(checkpoint.exists());
+
+    // Documents written by the previous run and unchanged since are skipped
+    let content = std::fs::read(&article_file).unwrap();
+    std::fs::write(&article_file, &content[..content.len() - 3]).unwrap();
+    let output = env.run(&[]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Edited 1 files\nSkipped 1 files already written by an interrupted sync")
+    );
+    let article = std::fs::read_to_string(&article_file).unwrap();
+    assert!(article.contains("A new highlight"));
+    assert!(!checkpoint.exists());
+}
+
+#[test]
+fn test_invalid_api_key_fails() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("invalid-key", &server.base_url, "");
+    let output = env
+        .command(&[])
+        .env("READWISE_API_KEY", "wrong")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(env.org_files().is_empty());
+}
+
+#[test]
+fn test_token_file() {
+    use std::os::unix::fs::PermissionsExt;
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new(
+        "token-file",
+        &server.base_url,
+        "token.source = \"file\"\ntoken.path = \"token\"",
+    );
+    let token_file = env.config_dir.join("token");
+    std::fs::write(&token_file, format!("{}\n", common::API_KEY)).unwrap();
+
+    // A token file readable by other users is refused
+    std::fs::set_permissions(&token_file, std::fs::Permissions::from_mode(0o644)).unwrap();
+    let output = env.run(&[]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("chmod 600"));
+    assert!(!stderr.contains(common::API_KEY));
+    assert!(server.requests().is_empty());
+
+    std::fs::set_permissions(&token_file, std::fs::Permissions::from_mode(0o600)).unwrap();
+    let output = env
+        .command(&[])
+        .env("READWISE_API_KEY", "wrong")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(env.org_files().len(), 3);
+}
+
+#[test]
+fn test_token_command() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new(
+        "token-command",
+        &server.base_url,
+        &format!(
+            "token.source = \"command\"\ntoken.command = [\"echo\", \"{}\"]",
+            common::API_KEY
+        ),
+    );
+    let output = env
+        .command(&[])
+        .env_remove("READWISE_API_KEY")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains(common::API_KEY));
+    assert_eq!(env.org_files().len(), 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_doctor_runs_the_token_command_once() {
+    let server = FakeReader::with_fixture();
+    let runs_path = std::env::temp_dir().join(format!(
+        "org-readwise-rust-token-runs-{}",
+        std::process::id()
+    ));
+    std::fs::remove_file(&runs_path).ok();
+    let env = TestEnv::new(
+        "token-command-doctor",
+        &server.base_url,
+        &format!(
+            "token.source = \"command\"\ntoken.command = [\"sh\", \"-c\", \"echo run >> '{}'; echo {}\"]",
+            runs_path.display(),
+            common::API_KEY
+        ),
+    );
+    let output = env
+        .command(&["doctor"])
+        .env_remove("READWISE_API_KEY")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("ok    API token: found"));
+    assert!(stdout.contains("ok    Reader API: "));
+    assert_eq!(std::fs::read_to_string(&runs_path).unwrap(), "run\n");
+    std::fs::remove_file(&runs_path).ok();
+}
+
+#[test]
+fn test_profiles() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("profiles", &server.base_url, "");
+    let dir_a = env.root.join("roam-a");
+    let dir_b = env.root.join("roam-b");
+    std::fs::create_dir_all(&dir_a).unwrap();
+    std::fs::create_dir_all(&dir_b).unwrap();
+    let config_path = env.config_dir.join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    let profiles = format!(
+        "\n[profiles.a]\norg_roam_dir = \"{}\"\n\n[profiles.b]\norg_roam_dir = \"{}\"\ndocument_categories = [\"epub\"]\n",
+        dir_a.display(),
+        dir_b.display()
+    );
+    std::fs::write(&config_path, config + &profiles).unwrap();
+    let org_files = |dir: &std::path::Path| std::fs::read_dir(dir).unwrap().count();
+
+    // Without --profile, every profile is synced, each with its own state
+    let output = env.run(&[]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("=== Profile a ===") && stdout.contains("=== Profile b ==="));
+    assert_eq!(org_files(&dir_a), 3);
+    assert_eq!(org_files(&dir_b), 1);
+    assert!(env.org_files().is_empty());
+    for name in ["a", "b"] {
+        let profile_dir = env.config_dir.join("profiles").join(name);
+        assert!(profile_dir.join("updated_after.txt").exists());
+        assert!(profile_dir.join("sync_state.json").exists());
+    }
+    assert!(!env.config_dir.join("updated_after.txt").exists());
+
+    // With --profile, only that one
+    std::fs::remove_dir_all(env.config_dir.join("profiles/b")).unwrap();
+    let requests_before = server.requests().len();
+    assert!(env.run(&["--profile", "a"]).status.success());
+    assert!(server.requests()[requests_before..]
+        .iter()
+        .all(|r| r.contains("updatedAfter=")));
+    assert!(!env.config_dir.join("profiles/b").exists());
+
+    let output = env.run(&["--profile", "c"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unknown profile: c"));
+
+    // One profile failing is a partial success
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(
+        &config_path,
+        config + "token = { source = \"command\", command = [\"echo\", \"wrong\"] }\n",
+    )
+    .unwrap();
+    let output = env.run(&[]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_profiles_dont_share_an_absolute_cursor() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("profiles-cursor", &server.base_url, "");
+    let shared = env.root.join("updated_after.txt");
+    let own = env.root.join("updated_after_b.txt");
+    let config_path = env.config_dir.join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap().replace(
+        "updated_after_file_path = \"updated_after.txt\"",
+        &format!("updated_after_file_path = \"{}\"", shared.display()),
+    );
+    let profiles = format!(
+        "\n[profiles.a]\ndocument_categories = [\"article\"]\n\n[profiles.b]\nupdated_after_file_path = \"{}\"\n",
+        own.display()
+    );
+    std::fs::write(&config_path, config + &profiles).unwrap();
+
+    // Profile a would use the top-level cursor, which b (or any other profile) could share
+    let output = env.run(&[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("profiles.a: updated_after_file_path = ")
+    );
+    assert!(own.exists());
+    assert!(!shared.exists());
+
+    let config = std::fs::read_to_string(&config_path).unwrap().replace(
+        "[profiles.a]\n",
+        "[profiles.a]\nupdated_after_file_path = \"updated_after_a.txt\"\n",
+    );
+    std::fs::write(&config_path, config).unwrap();
+    assert!(env.run(&[]).status.success());
+    assert!(env
+        .config_dir
+        .join("profiles/a/updated_after_a.txt")
+        .exists());
+    assert!(!shared.exists());
+}
+
+#[test]
+fn test_dry_run_writes_nothing() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("dry-run", &server.base_url, "");
+    let output = env.run(&["--dry-run"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // New files are shown in full, as a diff from /dev/null
+    assert!(stdout.contains("--- /dev/null\n+++ "));
+    assert!(stdout.contains("+First highlight of the article\n"));
+    assert!(stdout.contains("Would save next updated_after date: 20"));
+    assert!(stdout.contains("Dry run, nothing was written\nCreated 3 files"));
+    assert!(env.org_files().is_empty());
+    let written: Vec<_> = std::fs::read_dir(&env.config_dir)
+        .unwrap()
+        .flatten()
+        .map(|e| e.file_name())
+        .collect();
+    assert_eq!(written, vec!["config.toml"]);
+
+    assert!(env.run(&[]).status.success());
+    let article_file = env.org_file_containing("Theses on testing");
+    let content = std::fs::read_to_string(&article_file).unwrap();
+    server.upsert(json!({
+        "id": "01hl0article2000000000000",
+        "category": "highlight",
+        "parent_id": ARTICLE_ID,
+        "content": "Second highlight, edited",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+    let updated_after = std::fs::read_to_string(env.config_dir.join("updated_after.txt")).unwrap();
+
+    // Edits are shown as a unified diff of the file
+    let output = env.run(&["sync", "--dry-run"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let file_name = article_file.to_string_lossy();
+    assert!(stdout.contains(&format!("--- {}\n+++ {}\n@@ ", file_name, file_name)));
+    assert!(stdout.contains("-Second highlight of the article\n+Second highlight, edited\n"));
+    assert_eq!(std::fs::read_to_string(&article_file).unwrap(), content);
+    assert_eq!(
+        std::fs::read_to_string(env.config_dir.join("updated_after.txt")).unwrap(),
+        updated_after
+    );
+
+    // The change wasn't saved in the local copy either, so a real sync still applies it
+    assert!(env.run(&["render", "--dry-run"]).status.success());
+    assert!(env.run(&[]).status.success());
+    assert!(std::fs::read_to_string(&article_file)
+        .unwrap()
+        .contains("Second highlight, edited"));
+}
+
+#[test]
+fn test_undo_last_run_restores_files() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("undo", &server.base_url, "");
+    assert!(env.run(&[]).status.success());
+    let article_file = env.org_file_containing("Theses on testing");
+    let content = std::fs::read_to_string(&article_file).unwrap();
+    let updated_after_path = env.config_dir.join("updated_after.txt");
+    let updated_after = std::fs::read_to_string(&updated_after_path).unwrap();
+
+    server.upsert(json!({
+        "id": "01hl0article2000000000000",
+        "category": "highlight",
+        "parent_id": ARTICLE_ID,
+        "content": "Second highlight, edited",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+    assert!(env.run(&[]).status.success());
+    assert_ne!(std::fs::read_to_string(&article_file).unwrap(), content);
+
+    let output = env.run(&["undo-last-run"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("3 files restored, 0 removed"));
+    assert_eq!(std::fs::read_to_string(&article_file).unwrap(), content);
+    assert_eq!(
+        std::fs::read_to_string(&updated_after_path).unwrap(),
+        updated_after
+    );
+
+    // Undoing the first run removes the files it created
+    assert!(env.run(&["undo-last-run"]).status.success());
+    assert!(env.org_files().is_empty());
+    assert!(!updated_after_path.exists());
+    assert_eq!(env.run(&["undo-last-run"]).status.code(), Some(1));
+
+    // The next sync applies the changes again
+    assert!(env.run(&[]).status.success());
+    assert!(
+        std::fs::read_to_string(env.org_file_containing("Theses on testing"))
+            .unwrap()
+            .contains("Second highlight, edited")
+    );
+}
+
+#[test]
+fn test_git_auto_commit() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("git", &server.base_url, "[git]\nauto_commit = true");
+    let output = env.run(&[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("isn't in a git repository"));
+
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(&env.org_roam_dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    git(&["init", "--quiet"]);
+    git(&["config", "user.name", "Test"]);
+    git(&["config", "user.email", "test@example.com"]);
+    std::fs::write(env.org_roam_dir.join("other.org"), "* Not from Readwise\n").unwrap();
+    git(&["add", "other.org"]);
+
+    assert!(env.run(&[]).status.success());
+    let message = git(&["log", "-1", "--format=%B"]);
+    assert!(message.starts_with("Readwise sync: 3 files created, 0 edited\n\n"));
+    assert!(message.contains("Created Theses on testing ("));
+    // Only the files of the run were committed, the staged file is still staged
+    assert_eq!(git(&["diff", "--cached", "--name-only"]), "other.org\n");
+    assert_eq!(git(&["status", "--porcelain"]), "A  other.org\n");
+
+    // A file with uncommitted changes isn't touched, and is retried on the next sync
+    let article_file = env.org_file_containing("Theses on testing");
+    let edited = std::fs::read_to_string(&article_file).unwrap() + "My own note\n";
+    std::fs::write(&article_file, &edited).unwrap();
+    server.upsert(json!({
+        "id": "01hl0article2000000000000",
+        "category": "highlight",
+        "parent_id": ARTICLE_ID,
+        "content": "Second highlight, edited",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+    let output = env.run(&[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("has uncommitted changes"));
+    assert_eq!(std::fs::read_to_string(&article_file).unwrap(), edited);
+    assert_eq!(git(&["rev-list", "--count", "HEAD"]), "1\n");
+
+    git(&["commit", "--quiet", "-am", "My note"]);
+    assert!(env.run(&[]).status.success());
+    assert!(
+        git(&["log", "-1", "--format=%s"]).starts_with("Readwise sync: 0 files created, 1 edited")
+    );
+    assert!(std::fs::read_to_string(&article_file)
+        .unwrap()
+        .contains("Second highlight, edited"));
+}
+
+#[test]
+fn test_git_auto_commit_resumes_an_interrupted_sync() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("git-resume", &server.base_url, "[git]\nauto_commit = true");
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(&env.org_roam_dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    git(&["init", "--quiet"]);
+    git(&["config", "user.name", "Test"]);
+    git(&["config", "user.email", "test@example.com"]);
+    assert!(env.run(&[]).status.success());
+
+    // The article fails, the book is written
+    let article_file = env.org_file_containing("Theses on testing");
+    let mut content = std::fs::read(&article_file).unwrap();
+    content.extend_from_slice(b"\xff\xfe\n");
+    std::fs::write(&article_file, content).unwrap();
+    git(&["commit", "--quiet", "-am", "Not UTF-8"]);
+    server.upsert(json!({
+        "id": "01hl0article4000000000000",
+        "category": "highlight",
+        "parent_id": ARTICLE_ID,
+        "content": "A new highlight",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+    server.upsert(json!({
+        "id": "01hl0book2000000000000000",
+        "category": "highlight",
+        "parent_id": "01doc0book000000000000000",
+        "content": "Another highlight from the book",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+    assert_eq!(env.run(&[]).status.code(), Some(2));
+    // Like a sync killed before committing
+    git(&["reset", "--quiet", "HEAD~1"]);
+    let book_file = env.org_file_containing("A book (2003)");
+    assert!(git(&["status", "--porcelain"]).contains(" M "));
+
+    // The resumed sync commits the file written before it was interrupted
+    let content = std::fs::read(&article_file).unwrap();
+    std::fs::write(&article_file, &content[..content.len() - 3]).unwrap();
+    git(&["commit", "--quiet", "-am", "Valid UTF-8"]);
+    let output = env.run(&[]);
+    assert!(output.status.success());
+    let message = git(&["log", "-1", "--format=%B"]);
+    assert!(message.starts_with("Readwise sync: 0 files created, 1 edited\n\n"));
+    assert!(message.contains("Written by an interrupted sync: A book"));
+    assert_eq!(git(&["status", "--porcelain"]), "");
+
+    // And the file can be edited by the next syncs
+    server.upsert(json!({
+        "id": "01hl0book3000000000000000",
+        "category": "highlight",
+        "parent_id": "01doc0book000000000000000",
+        "content": "A third highlight from the book",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+    assert!(env.run(&[]).status.success());
+    assert!(std::fs::read_to_string(&book_file)
+        .unwrap()
+        .contains("A third highlight from the book"));
+    assert_eq!(git(&["status", "--porcelain"]), "");
+}
+
+#[test]
+fn test_git_auto_commit_doesnt_commit_edits_made_after_a_partial_sync() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("git-partial", &server.base_url, "[git]\nauto_commit = true");
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(&env.org_roam_dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    git(&["init", "--quiet"]);
+    git(&["config", "user.name", "Test"]);
+    git(&["config", "user.email", "test@example.com"]);
+    assert!(env.run(&[]).status.success());
+
+    // The article fails, the book is written and committed
+    let article_file = env.org_file_containing("Theses on testing");
+    let mut content = std::fs::read(&article_file).unwrap();
+    content.extend_from_slice(b"\xff\xfe\n");
+    std::fs::write(&article_file, content).unwrap();
+    git(&["commit", "--quiet", "-am", "Not UTF-8"]);
+    server.upsert(json!({
+        "id": "01hl0article4000000000000",
+        "category": "highlight",
+        "parent_id": ARTICLE_ID,
+        "content": "A new highlight",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+    server.upsert(json!({
+        "id": "01hl0book2000000000000000",
+        "category": "highlight",
+        "parent_id": "01doc0book000000000000000",
+        "content": "Another highlight from the book",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+    assert_eq!(env.run(&[]).status.code(), Some(2));
+    assert_eq!(git(&["status", "--porcelain"]), "");
+
+    // An edit of the book made before the next sync isn't committed by it
+    let book_file = env.org_file_containing("A book (2003)");
+    let edited = std::fs::read_to_string(&book_file).unwrap() + "* My private edit\n";
+    std::fs::write(&book_file, &edited).unwrap();
+    let content = std::fs::read(&article_file).unwrap();
+    std::fs::write(&article_file, &content[..content.len() - 3]).unwrap();
+    git(&[
+        "commit",
+        "--quiet",
+        "-m",
+        "Valid UTF-8",
+        "--",
+        article_file.to_str().unwrap(),
+    ]);
+    let output = env.run(&[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("has uncommitted changes"));
+    assert_eq!(std::fs::read_to_string(&book_file).unwrap(), edited);
+    assert!(!git(&["log", "-p", "--format=%s"]).contains("My private edit"));
+    let status = git(&["status", "--porcelain"]);
+    assert_eq!(status.lines().count(), 1);
+    assert!(status.starts_with(" M "));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_files_being_edited_in_emacs_are_deferred() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new("emacs-lock", &server.base_url, "");
+    assert!(env.run(&[]).status.success());
+    let article_file = env.org_file_containing("Theses on testing");
+    let content = std::fs::read_to_string(&article_file).unwrap();
+    let name = article_file.file_name().unwrap().to_string_lossy();
+    // Like Emacs, a dangling symlink
+    let lock = article_file.with_file_name(format!(".#{}", name));
+    std::os::unix::fs::symlink("user@host.1234:1700000000", &lock).unwrap();
+    let updated_after_path = env.config_dir.join("updated_after.txt");
+    let updated_after = std::fs::read_to_string(&updated_after_path).unwrap();
+    server.upsert(json!({
+        "id": "01hl0article2000000000000",
+        "category": "highlight",
+        "parent_id": ARTICLE_ID,
+        "content": "Second highlight, edited",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+
+    let output = env.run(&[]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("1 documents deferred until their file is saved in Emacs:"));
+    assert!(stdout.contains(&format!("{} exists", lock.display())));
+    assert_eq!(std::fs::read_to_string(&article_file).unwrap(), content);
+    assert_eq!(
+        std::fs::read_to_string(&updated_after_path).unwrap(),
+        updated_after
+    );
+
+    // So is a file with an autosave file
+    std::fs::remove_file(&lock).unwrap();
+    let autosave = article_file.with_file_name(format!("#{}#", name));
+    std::fs::write(&autosave, "unsaved").unwrap();
+    assert!(String::from_utf8_lossy(&env.run(&[]).stdout).contains("1 documents deferred"));
+    assert_eq!(std::fs::read_to_string(&article_file).unwrap(), content);
+
+    // Once saved, the next sync picks the document up
+    std::fs::remove_file(&autosave).unwrap();
+    let output = env.run(&[]);
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("deferred"));
+    assert!(std::fs::read_to_string(&article_file)
+        .unwrap()
+        .contains("Second highlight, edited"));
+    assert_ne!(
+        std::fs::read_to_string(&updated_after_path).unwrap(),
+        updated_after
+    );
+}
+
+#[test]
+fn test_annotations_survive_an_upstream_edit_of_the_highlight() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new(
+        "annotations",
+        &server.base_url,
+        "preserve_annotations = true",
+    );
+    assert!(env.run(&[]).status.success());
+    let article_file = env.org_file_containing("Theses on testing");
+    let content = std::fs::read_to_string(&article_file).unwrap();
+    let annotated = content
+        .replace(
+            "Second highlight of the article\n",
+            "Second highlight of the article\n- my note\n",
+        )
+        .replace(
+            "My note on the first highlight\n",
+            "My note on the first highlight\n- my reply to the note\n",
+        );
+    assert_eq!(
+        annotated.len(),
+        content.len() + "- my note\n- my reply to the note\n".len()
+    );
+    std::fs::write(&article_file, annotated).unwrap();
+
+    server.upsert(json!({
+        "id": "01hl0article2000000000000",
+        "category": "highlight",
+        "parent_id": ARTICLE_ID,
+        "content": "Second highlight, edited",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+    server.upsert(json!({
+        "id": "01note0article10000000000",
+        "category": "note",
+        "parent_id": "01hl0article1000000000000",
+        "content": "My note, edited",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+    assert!(env.run(&[]).status.success());
+    let content = std::fs::read_to_string(&article_file).unwrap();
+    assert!(content.contains("Second highlight, edited\n- my note"));
+    assert!(!content.contains("Second highlight of the article"));
+    assert!(content.contains("My note, edited\n- my reply to the note"));
+    assert!(!content.contains("My note on the first highlight"));
+}
+
+#[test]
+fn test_deleted_documents_are_tagged() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new(
+        "deleted-tag",
+        &server.base_url,
+        "deleted_documents = \"tag\"",
+    );
+    assert!(env.run(&[]).status.success());
+    let article_file = env.org_file_containing("Theses on testing");
+    let book_file = env.org_file_containing("A book");
+
+    server.remove(ARTICLE_ID);
+    // A run that fails doesn't touch the file
+    server.upsert(json!({
+        "id": "01hl0broken0000000000000",
+        "category": "highlight",
+        "content": "A highlight without a parent",
+        "saved_at": now(),
+        "updated_at": now(),
+    }));
+    assert!(!env.run(&["--strict"]).status.success());
+    assert!(!std::fs::read_to_string(&article_file)
+        .unwrap()
+        .contains(":readwise_deleted:"));
+    assert!(env.run(&[]).status.success());
+    assert!(std::fs::read_to_string(&article_file)
+        .unwrap()
+        .contains(":readwise_deleted:"));
+    assert!(!std::fs::read_to_string(&book_file)
+        .unwrap()
+        .contains(":readwise_deleted:"));
+}
+
+#[test]
+fn test_deleted_documents_are_moved_but_not_those_of_removed_categories() {
+    let server = FakeReader::with_fixture();
+    let env = TestEnv::new(
+        "deleted-move",
+        &server.base_url,
+        "deleted_documents = \"move\"",
+    );
+    assert!(env.run(&[]).status.success());
+    let article_file = env.org_file_containing("Theses on testing");
+    let book_file = env.org_file_containing("A book");
+
+    // A file that was already retired under the same name isn't replaced
+    let deleted_dir = env.org_roam_dir.join("readwise_deleted");
+    let file_name = article_file.file_name().unwrap().to_string_lossy();
+    std::fs::create_dir_all(&deleted_dir).unwrap();
+    std::fs::write(deleted_dir.join(file_name.as_ref()), "Retired before\n").unwrap();
+
+    server.remove(ARTICLE_ID);
+    assert!(env.run(&[]).status.success());
+    assert!(!article_file.exists());
+    assert_eq!(
+        std::fs::read_to_string(deleted_dir.join(file_name.as_ref())).unwrap(),
+        "Retired before\n"
+    );
+    let moved = deleted_dir.join(file_name.replace(".org", "-1.org"));
+    assert!(std::fs::read_to_string(moved)
+        .unwrap()
+        .contains("Theses on testing"));
+
+    // No longer syncing a category doesn't mean its documents were deleted
+    let config_path = env.config_dir.join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap().replace(
+        "document_categories = [\"epub\", \"article\"]",
+        "document_categories = [\"article\"]",
+    );
+    std::fs::write(&config_path, config).unwrap();
+    assert!(env.run(&[]).status.success());
+    assert!(book_file.exists());
+}
+
+#[test]
+fn test_deleted_highlights_are_commented_out_or_moved() {
+    for policy in ["comment", "move"] {
+        let server = FakeReader::with_fixture();
+        let env = TestEnv::new(
+            &format!("deleted-highlight-{}", policy),
+            &server.base_url,
+            // A full fetch on every sync, which is how deleted highlights are found
+            &format!("deleted_highlights = \"{}\"\nfull_refresh_days = 0", policy),
+        );
+        assert!(env.run(&[]).status.success());
+        let article_file = env.org_file_containing("Theses on testing");
+
+        server.remove("01hl0article2000000000000");
+        assert!(env.run(&[]).status.success());
+        let content = std::fs::read_to_string(&article_file).unwrap();
+        assert!(content.contains("** readwise:01hl0article1000000000000"));
+        let heading = "readwise:01hl0article2000000000000\nSecond highlight of the article";
+        if policy == "comment" {
+            assert!(content.contains(&format!("** COMMENT {}", heading)));
+        } else {
+            let deleted_section = content.find("\n* readwise:deleted\n").unwrap();
+            assert!(content.find(&format!("** {}", heading)).unwrap() > deleted_section);
+        }
+    }
+}